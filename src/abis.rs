@@ -1,2 +1,64 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use serde_json::Value;
+
+pub mod decode;
+
 pub const ERC20: &str = include_str!("../abis/erc20.json");
 pub const ERC721: &str = include_str!("../abis/erc721.json");
+pub const ERC1155: &str = include_str!("../abis/erc1155.json");
+pub const WETH: &str = include_str!("../abis/weth.json");
+pub const UNISWAP_V3_POOL: &str = include_str!("../abis/uniswap_v3_pool.json");
+
+/// Reads an ABI json file and adds it to the abis under the name
+///
+/// The file has to be a json array of ABI entries, like the ones solc outputs.
+pub fn load_abi(
+    abis: &mut HashMap<String, String>,
+    name: String,
+    path: &Path,
+) -> Result<(), String> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("The ABI needs a name".to_string());
+    }
+    if abis.contains_key(&name) {
+        return Err(format!("An ABI named `{}` is already loaded", name));
+    }
+
+    let json = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    validate_abi(&json).map_err(|err| format!("{} isn't a valid ABI: {}", path.display(), err))?;
+
+    abis.insert(name, json);
+    Ok(())
+}
+
+/// Checks the json is an array of ABI entries, each an object with a `type`
+fn validate_abi(json: &str) -> Result<(), String> {
+    let value: Value = serde_json::from_str(json).map_err(|err| err.to_string())?;
+
+    let Value::Array(entries) = value else {
+        return Err("expected a json array".to_string());
+    };
+
+    for (i, entry) in entries.iter().enumerate() {
+        if !entry.get("type").is_some_and(Value::is_string) {
+            return Err(format!("entry {} has no `type`", i));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_abis_are_valid() {
+        for abi in [ERC20, ERC721, ERC1155, WETH, UNISWAP_V3_POOL] {
+            validate_abi(abi).unwrap();
+        }
+    }
+}
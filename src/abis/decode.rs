@@ -0,0 +1,345 @@
+//! Decodes event logs with an ABI, and the rhai `decode_log(abi_name, log)` binding for handlers
+//!
+//! The log is the json of a log from an Ethereum block, with hex `topics` and `data`.
+//! Decoding covers the common types: address, bool, intN, uintN, bytesN, bytes, string, and arrays of static types.
+//! Integers are returned as decimal strings, so 256 bit values don't lose precision.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use rhai::{Dynamic, Engine, EvalAltResult};
+use serde_json::{json, Map, Value};
+use tiny_keccak::{Hasher, Keccak};
+
+/// The ABI json of every loaded ABI, by name
+pub type AbiSources = Arc<RwLock<HashMap<String, String>>>;
+
+/// Registers `decode_log(abi_name, log)`, which decodes with whatever ABIs are in `abis` when it's called
+pub fn register(engine: &mut Engine, abis: AbiSources) {
+    engine.register_fn(
+        "decode_log",
+        move |abi_name: &str, log: Dynamic| -> Result<Dynamic, Box<EvalAltResult>> {
+            let abis = abis.read().unwrap();
+            let abi = abis
+                .get(abi_name)
+                .ok_or_else(|| format!("No ABI named `{}`", abi_name))?;
+
+            let log: Value = rhai::serde::from_dynamic(&log)?;
+            let decoded = decode_log(abi, &log)?;
+            rhai::serde::to_dynamic(decoded)
+        },
+    );
+}
+
+/// Decodes a log with the event in the ABI whose signature matches its first topic
+pub fn decode_log(abi: &str, log: &Value) -> Result<Value, String> {
+    let abi: Value = serde_json::from_str(abi).map_err(|err| err.to_string())?;
+
+    let topics = log
+        .get("topics")
+        .and_then(Value::as_array)
+        .ok_or("The log has no `topics`")?
+        .iter()
+        .map(|topic| {
+            topic
+                .as_str()
+                .ok_or("Topics must be hex strings".to_string())
+                .and_then(from_hex)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let data = match log.get("data").and_then(Value::as_str) {
+        Some(data) => from_hex(data)?,
+        None => Vec::new(),
+    };
+
+    let selector = topics
+        .first()
+        .ok_or("The log has no topics, so it can't be matched to an event")?;
+
+    let event = abi
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|entry| entry.get("type").and_then(Value::as_str) == Some("event"))
+        .find(|event| {
+            event_signature(event)
+                .map(|sig| keccak(sig.as_bytes()))
+                .as_ref()
+                == Some(selector)
+        })
+        .ok_or_else(|| format!("No event in the ABI matches topic 0x{}", to_hex(selector)))?;
+
+    let inputs = event
+        .get("inputs")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut params = Map::new();
+    let mut indexed_topics = topics.iter().skip(1);
+    let mut data_types = Vec::new();
+    for (i, input) in inputs.iter().enumerate() {
+        let name = match input.get("name").and_then(Value::as_str) {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => format!("param{}", i),
+        };
+        let kind = input
+            .get("type")
+            .and_then(Value::as_str)
+            .ok_or("An event input has no `type`")?;
+
+        if input
+            .get("indexed")
+            .and_then(Value::as_bool)
+            .unwrap_or(false)
+        {
+            let topic = indexed_topics
+                .next()
+                .ok_or_else(|| format!("The log is missing the topic for `{}`", name))?;
+            // Dynamic values are hashed when indexed, so the topic is all there is
+            let value = if is_dynamic(kind) {
+                json!(format!("0x{}", to_hex(topic)))
+            } else {
+                decode_word(kind, topic)?
+            };
+            params.insert(name, value);
+        } else {
+            data_types.push((name, kind));
+        }
+    }
+
+    for (i, (name, kind)) in data_types.into_iter().enumerate() {
+        let value = decode_param(kind, &data, i * 32)?;
+        params.insert(name, value);
+    }
+
+    Ok(json!({
+        "event": event.get("name").cloned().unwrap_or(Value::Null),
+        "params": params,
+    }))
+}
+
+/// The canonical signature of an event, eg `Transfer(address,address,uint256)`
+fn event_signature(event: &Value) -> Option<String> {
+    let name = event.get("name")?.as_str()?;
+    let types = event
+        .get("inputs")?
+        .as_array()?
+        .iter()
+        .map(|input| input.get("type")?.as_str())
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("{}({})", name, types.join(",")))
+}
+
+fn keccak(bytes: &[u8]) -> Vec<u8> {
+    let mut hasher = Keccak::v256();
+    let mut output = [0u8; 32];
+    hasher.update(bytes);
+    hasher.finalize(&mut output);
+    output.to_vec()
+}
+
+fn is_dynamic(kind: &str) -> bool {
+    kind == "string" || kind == "bytes" || kind.ends_with("[]")
+}
+
+/// Decodes the parameter whose head is at `offset` in the data
+fn decode_param(kind: &str, data: &[u8], offset: usize) -> Result<Value, String> {
+    let head = word(data, offset)?;
+    if !is_dynamic(kind) {
+        return decode_word(kind, head);
+    }
+
+    // Dynamic values store the offset of their length prefixed content in the head
+    let start = to_usize(head)?;
+    let len = to_usize(word(data, start)?)?;
+    let content = start + 32;
+
+    match kind {
+        "string" => {
+            let bytes = slice(data, content, len)?;
+            Ok(json!(String::from_utf8_lossy(bytes)))
+        }
+        "bytes" => Ok(json!(format!("0x{}", to_hex(slice(data, content, len)?)))),
+        _ => {
+            let item = &kind[..kind.len() - 2];
+            if is_dynamic(item) {
+                return Err(format!("Unsupported type {}", kind));
+            }
+            (0..len)
+                .map(|i| decode_word(item, word(data, content + i * 32)?))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Value::Array)
+        }
+    }
+}
+
+/// Decodes a static value from its 32 byte word
+fn decode_word(kind: &str, word: &[u8]) -> Result<Value, String> {
+    // Topics come straight from the log, so their length has to be checked before indexing into them
+    if word.len() != 32 {
+        return Err(format!(
+            "A {} value must be a 32 byte word, not {} bytes",
+            kind,
+            word.len()
+        ));
+    }
+
+    if kind == "address" {
+        Ok(json!(format!("0x{}", to_hex(&word[12..]))))
+    } else if kind == "bool" {
+        Ok(json!(word.iter().any(|b| *b != 0)))
+    } else if kind.starts_with("uint") {
+        Ok(json!(to_decimal(word)))
+    } else if kind.starts_with("int") {
+        if word[0] & 0x80 == 0 {
+            Ok(json!(to_decimal(word)))
+        } else {
+            // Two's complement, so the magnitude is the inverse plus one
+            let mut magnitude = word.iter().map(|b| !b).collect::<Vec<_>>();
+            for byte in magnitude.iter_mut().rev() {
+                let (sum, carry) = byte.overflowing_add(1);
+                *byte = sum;
+                if !carry {
+                    break;
+                }
+            }
+            Ok(json!(format!("-{}", to_decimal(&magnitude))))
+        }
+    } else if let Some(size) = kind.strip_prefix("bytes") {
+        let size = size
+            .parse::<usize>()
+            .map_err(|_| format!("Unsupported type {}", kind))?;
+        Ok(json!(format!("0x{}", to_hex(&word[..size.min(32)]))))
+    } else {
+        Err(format!("Unsupported type {}", kind))
+    }
+}
+
+fn word(data: &[u8], offset: usize) -> Result<&[u8], String> {
+    slice(data, offset, 32)
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], String> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| "The log data is shorter than the event needs".to_string())
+}
+
+fn to_usize(word: &[u8]) -> Result<usize, String> {
+    let (high, low) = word.split_at(24);
+    if high.iter().any(|b| *b != 0) {
+        return Err("An offset in the log data is too large".to_string());
+    }
+    Ok(u64::from_be_bytes(low.try_into().unwrap()) as usize)
+}
+
+/// Formats a big endian unsigned integer of any size in decimal
+fn to_decimal(bytes: &[u8]) -> String {
+    let mut number = bytes.to_vec();
+    let mut digits = Vec::new();
+
+    while number.iter().any(|b| *b != 0) {
+        let mut remainder = 0u32;
+        for byte in number.iter_mut() {
+            let value = (remainder << 8) | *byte as u32;
+            *byte = (value / 10) as u8;
+            remainder = value % 10;
+        }
+        digits.push(b'0' + remainder as u8);
+    }
+
+    if digits.is_empty() {
+        return "0".to_string();
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    // Checked before slicing, since a multibyte character would put a slice inside it
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("`{}` isn't valid hex", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("`{}` isn't valid hex", hex))
+        })
+        .collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ERC20_TRANSFER_ABI: &str = r#"[{
+        "type": "event",
+        "name": "Transfer",
+        "anonymous": false,
+        "inputs": [
+            {"name": "from", "type": "address", "indexed": true},
+            {"name": "to", "type": "address", "indexed": true},
+            {"name": "value", "type": "uint256", "indexed": false}
+        ]
+    }]"#;
+
+    /// `keccak("Transfer(address,address,uint256)")`
+    const TRANSFER_TOPIC: &str =
+        "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+    fn transfer_log(from_topic: &str) -> Value {
+        json!({
+            "topics": [
+                TRANSFER_TOPIC,
+                from_topic,
+                "0x000000000000000000000000c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            ],
+            "data": "0x00000000000000000000000000000000000000000000000000000000000003e8",
+        })
+    }
+
+    #[test]
+    fn decodes_an_erc20_transfer() {
+        let from = "0x000000000000000000000000a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+        let decoded = decode_log(ERC20_TRANSFER_ABI, &transfer_log(from)).unwrap();
+
+        assert_eq!(
+            decoded,
+            json!({
+                "event": "Transfer",
+                "params": {
+                    "from": "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+                    "to": "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+                    "value": "1000",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_a_topic_that_isnt_a_word() {
+        let err = decode_log(ERC20_TRANSFER_ABI, &transfer_log("0x")).unwrap_err();
+        assert!(err.contains("32 byte word"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_non_ascii_hex() {
+        let err = decode_log(ERC20_TRANSFER_ABI, &transfer_log("0xé0")).unwrap_err();
+        assert!(err.contains("isn't valid hex"), "{}", err);
+    }
+
+    #[test]
+    fn decodes_negative_ints() {
+        let word = [0xff; 32];
+        assert_eq!(decode_word("int256", &word).unwrap(), json!("-1"));
+    }
+}
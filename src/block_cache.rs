@@ -1,77 +1,368 @@
-use std::sync::mpsc::Sender;
-
-use eframe::egui::{self, Response, Ui, Widget, Window};
+use eframe::egui::{self, ComboBox, Response, ScrollArea, Ui, Widget, Window};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{EditorState, StreamMessages};
+use crate::{
+    tasks::Channels,
+    test_data::{self, BlockParams},
+    EditorState, Endpoint, StreamMessages, WorkerMessage,
+};
 
 const ETH_BLOCK_SUBSTREAM: &str = "https://spkg.io/streamingfast/ethereum-explorer-v0.1.2.spkg";
 
-#[derive(Serialize, Deserialize, Default)]
+/// Finds the block number in a block or module output, looking at the usual places it shows up
+///
+/// Proto3 json encodes 64 bit integers as strings, so both numbers and numeric strings are accepted.
+pub fn block_number(value: &Value) -> Option<u64> {
+    let number = value
+        .pointer("/clock/number")
+        .or_else(|| value.pointer("/header/number"))
+        .or_else(|| value.get("number"))?;
+
+    match number {
+        Value::Number(number) => number.as_u64(),
+        Value::String(number) => number.parse().ok(),
+        _ => None,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct BlockCacheUiState {
     block_number: u64,
-    cache_index: u8,
+    cache_index: usize,
+    /// Index into the endpoint list to fetch from, instead of the selected endpoint
+    #[serde(default)]
+    endpoint_override: Option<usize>,
+    #[serde(default)]
+    generator: BlockParams,
+    /// How many blocks a range fetch gets
+    #[serde(default = "default_range_count")]
+    range_count: u64,
+    /// Lets a range fetch replace slots that already hold a block
+    #[serde(default)]
+    overwrite_range: bool,
+}
+
+fn default_range_count() -> u64 {
+    4
+}
+
+impl Default for BlockCacheUiState {
+    fn default() -> Self {
+        Self {
+            block_number: 0,
+            cache_index: 0,
+            endpoint_override: None,
+            generator: BlockParams::default(),
+            range_count: default_range_count(),
+            overwrite_range: false,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Default)]
+/// How many empty slots a new cache starts with
+const DEFAULT_SLOTS: usize = 4;
+
+#[derive(Serialize, Deserialize)]
+#[serde(from = "SavedBlockCache")]
 pub struct BlockCache {
+    slots: Vec<Value>,
+    state: BlockCacheUiState,
+}
+
+impl Default for BlockCache {
+    fn default() -> Self {
+        Self {
+            slots: vec![Value::Null; DEFAULT_SLOTS],
+            state: BlockCacheUiState::default(),
+        }
+    }
+}
+
+/// The saved form of the cache, which also accepts the old fixed `block_1`..`block_4` layout
+#[derive(Deserialize)]
+struct SavedBlockCache {
+    slots: Option<Vec<Value>>,
+    #[serde(default)]
     block_1: Value,
+    #[serde(default)]
     block_2: Value,
+    #[serde(default)]
     block_3: Value,
+    #[serde(default)]
     block_4: Value,
+    #[serde(default)]
     state: BlockCacheUiState,
 }
 
+impl From<SavedBlockCache> for BlockCache {
+    fn from(saved: SavedBlockCache) -> Self {
+        let slots = saved
+            .slots
+            .unwrap_or_else(|| vec![saved.block_1, saved.block_2, saved.block_3, saved.block_4]);
+
+        Self {
+            slots,
+            state: saved.state,
+        }
+    }
+}
+
 impl BlockCache {
-    pub fn set(&mut self, block: u8, value: Value) {
-        match block {
-            1 => self.block_1 = value,
-            2 => self.block_2 = value,
-            3 => self.block_3 = value,
-            4 => self.block_4 = value,
-            _ => println!("Invalid block number"),
+    /// Sets the value of a slot, growing the cache if the slot doesn't exist yet
+    pub fn set(&mut self, slot: usize, value: Value) {
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, Value::Null);
         }
+        self.slots[slot] = value;
     }
 
-    pub fn get(&self, block: u8) -> &Value {
-        match block {
-            1 => &self.block_1,
-            2 => &self.block_2,
-            3 => &self.block_3,
-            4 => &self.block_4,
-            _ => {
-                println!("Invalid block number");
-                &Value::Null
-            }
+    /// Gets the value of a slot, or null if the slot doesn't exist
+    pub fn get(&self, slot: usize) -> &Value {
+        self.slots.get(slot).unwrap_or(&Value::Null)
+    }
+
+    /// Sets the block number the cache window fetches
+    pub fn set_fetch_block(&mut self, number: u64) {
+        self.state.block_number = number;
+    }
+
+    /// Empties a slot, keeping it allocated
+    pub fn clear(&mut self, slot: usize) {
+        if let Some(slot) = self.slots.get_mut(slot) {
+            *slot = Value::Null;
         }
     }
 
+    /// Empties every slot, keeping them allocated
+    pub fn clear_all(&mut self) {
+        self.slots.fill(Value::Null);
+    }
+
+    /// The number of the block in a slot, or `None` if the slot is empty or doesn't hold a recognizable block
+    pub fn block_number(&self, slot: usize) -> Option<u64> {
+        block_number(self.get(slot))
+    }
+
+    /// The number of allocated slots
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// Allocates a new empty slot, returning its index
+    pub fn add_slot(&mut self) -> usize {
+        self.slots.push(Value::Null);
+        self.slots.len() - 1
+    }
+
     pub fn show(
         &mut self,
         ui: &mut Ui,
         api_key: &str,
         endpoint: &str,
-        stream_sender: &Sender<StreamMessages>,
+        endpoints: &[Endpoint],
+        channels: Channels,
+        map_modules: &[(String, String)],
     ) -> Response {
-        let state = &mut self.state;
-        let mut temp = state.block_number.to_string();
-
-        ui.label("Block 1");
-        ui.horizontal(|ui| {
-            ui.text_edit_singleline(&mut temp);
-            if let Ok(temp) = temp.parse() {
-                state.block_number = temp;
-            };
-
-            if ui.button("Get").clicked() {
-                let message = StreamMessages::GetBlock {
-                    number: state.block_number as i64,
+        ui.vertical(|ui| {
+            ui.label("Slots");
+            let mut cleared = None;
+            ScrollArea::vertical().max_height(150.0).show(ui, |ui| {
+                for (i, slot) in self.slots.iter().enumerate() {
+                    let status = match block_number(slot) {
+                        Some(number) => format!("block {}", number),
+                        None if slot.is_null() => "empty".to_string(),
+                        None => "cached, not a block".to_string(),
+                    };
+                    ui.horizontal(|ui| {
+                        ui.label(format!("Slot {}: {}", i, status));
+                        if !slot.is_null() && ui.small_button("✕").on_hover_text("Clear").clicked()
+                        {
+                            cleared = Some(i);
+                        }
+                        if !slot.is_null() && !map_modules.is_empty() {
+                            ui.menu_button("Eval with…", |ui| {
+                                for (name, handler) in map_modules {
+                                    if ui.button(name).clicked() {
+                                        let message = WorkerMessage::EvalWithArgs(
+                                            name.clone(),
+                                            handler.clone(),
+                                            vec![slot.clone()],
+                                            false,
+                                        );
+                                        channels.worker.send(message).unwrap();
+                                        ui.close_menu();
+                                    }
+                                }
+                            });
+                        }
+                    });
+                }
+            });
+            if let Some(slot) = cleared {
+                self.clear(slot);
+            }
+            ui.horizontal(|ui| {
+                if ui.button("+").on_hover_text("Add a slot").clicked() {
+                    self.add_slot();
+                }
+                if ui.button("Clear All").clicked() {
+                    self.clear_all();
+                }
+            });
+
+            ui.separator();
+
+            let slot_count = self.slots.len();
+            let slots = &self.slots;
+            let state = &mut self.state;
+            let mut temp = state.block_number.to_string();
+
+            // The override may point past the end if endpoints were removed
+            let endpoint_override = state.endpoint_override.and_then(|i| endpoints.get(i));
+
+            ComboBox::from_label("Fetch From")
+                .selected_text(endpoint_override.map_or("Selected Endpoint", |e| &e.name))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut state.endpoint_override, None, "Selected Endpoint");
+                    for (i, endpoint) in endpoints.iter().enumerate() {
+                        ui.selectable_value(&mut state.endpoint_override, Some(i), &endpoint.name);
+                    }
+                });
+
+            let endpoint = endpoint_override.map_or(endpoint, |e| &e.url);
+
+            ComboBox::from_label("Into Slot")
+                .selected_text(format!("Slot {}", state.cache_index))
+                .show_ui(ui, |ui| {
+                    for i in 0..slot_count {
+                        ui.selectable_value(&mut state.cache_index, i, format!("Slot {}", i));
+                    }
+                });
+
+            ui.label("Fetch Block");
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut temp);
+                if let Ok(temp) = temp.parse() {
+                    state.block_number = temp;
+                };
+
+                if ui
+                    .button("Latest")
+                    .on_hover_text("Fill in the current chain head")
+                    .clicked()
+                {
+                    let message = StreamMessages::GetLatestBlock {
+                        api_key: api_key.to_string(),
+                        endpoint: endpoint.to_string(),
+                    };
+                    channels.stream.send(message).unwrap();
+                }
+
+                // The selected slot can be stale if the cache was replaced, eg by loading a project
+                let slot_valid = state.cache_index < slot_count;
+                let get = ui
+                    .add_enabled(slot_valid, egui::Button::new("Get"))
+                    .on_disabled_hover_text("Select a slot to fetch into");
+                if get.clicked() {
+                    let message = StreamMessages::GetBlock {
+                        number: state.block_number as i64,
+                        api_key: api_key.to_string(),
+                        endpoint: endpoint.to_string(),
+                        cache_slot: state.cache_index,
+                    };
+                    channels.stream.send(message).unwrap();
+                }
+            });
+
+            ui.label("Fetch Range");
+            ui.horizontal(|ui| {
+                ui.label("Blocks");
+                ui.add(egui::DragValue::new(&mut state.range_count).clamp_range(1..=64));
+            });
+
+            state.range_count = state.range_count.max(1);
+            // The range starts at the block and slot picked above, and adds slots when it runs past the end
+            let first_slot = state.cache_index;
+            let last_slot = first_slot + state.range_count as usize - 1;
+            let cached: Vec<usize> = (first_slot..=last_slot)
+                .filter(|&i| slots.get(i).is_some_and(|slot| !slot.is_null()))
+                .collect();
+            let added = (last_slot + 1).saturating_sub(slot_count);
+
+            let mut summary = format!(
+                "Blocks {} to {} into slots {} to {}",
+                state.block_number,
+                state.block_number + state.range_count - 1,
+                first_slot,
+                last_slot
+            );
+            if added > 0 {
+                summary.push_str(&format!(", adding {} slots", added));
+            }
+            ui.label(summary);
+
+            if !cached.is_empty() {
+                let slot_list: Vec<String> = cached.iter().map(|i| i.to_string()).collect();
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    format!("⚠ Slots {} already hold blocks", slot_list.join(", ")),
+                );
+                ui.checkbox(&mut state.overwrite_range, "Overwrite them");
+            }
+
+            let can_fetch = first_slot < slot_count && (cached.is_empty() || state.overwrite_range);
+            let get_range = ui
+                .add_enabled(can_fetch, egui::Button::new("Get Range"))
+                .on_disabled_hover_text("Select a slot, and allow overwriting any cached slots");
+            if get_range.clicked() {
+                let message = StreamMessages::GetBlockRange {
+                    start: state.block_number as i64,
+                    count: state.range_count,
                     api_key: api_key.to_string(),
                     endpoint: endpoint.to_string(),
-                    cache_slot: 1 as u8,
+                    first_slot,
                 };
-                stream_sender.send(message).unwrap();
+                channels.stream.send(message).unwrap();
+            }
+
+            let mut generate = false;
+            ui.collapsing("Generate Test Block", |ui| {
+                let params = &mut state.generator;
+                ui.horizontal(|ui| {
+                    ui.label("Block Number");
+                    ui.add(egui::DragValue::new(&mut params.number));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Transactions");
+                    ui.add(egui::DragValue::new(&mut params.transactions).clamp_range(0..=1000));
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Logs per Transaction");
+                    ui.add(
+                        egui::DragValue::new(&mut params.logs_per_transaction).clamp_range(0..=100),
+                    );
+                });
+                ui.label("Log Address");
+                ui.text_edit_singleline(&mut params.log_address);
+                ui.label("Log Topic");
+                ui.text_edit_singleline(&mut params.log_topic);
+
+                generate = ui
+                    .add_enabled(
+                        state.cache_index < slot_count,
+                        egui::Button::new(format!("Generate into Slot {}", state.cache_index)),
+                    )
+                    .clicked();
+            });
+
+            if generate {
+                let block = test_data::generate_block(&self.state.generator);
+                self.set(self.state.cache_index, block);
             }
         })
         .response
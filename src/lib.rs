@@ -1,43 +1,91 @@
 use std::{
-    collections::HashMap,
-    fs,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    fmt, fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
     sync::{
-        mpsc::{self, Sender},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender},
         Arc, Mutex, RwLock,
     },
     thread,
+    time::{Duration, Instant},
 };
 
+use abis::decode::AbiSources;
 use block_cache::BlockCache;
 use eframe::{
     egui::{self, ComboBox, Context, Frame, Key, Ui, Widget, Window},
     run_native, AppCreator, NativeOptions,
 };
+use local_store::LocalStore;
+use rand::random;
 
-use rhai::{eval, Dynamic, Engine, EvalAltResult, FuncArgs, OptimizationLevel, Scope, AST};
+use rhai::{
+    eval, Dynamic, Engine, EvalAltResult, FuncArgs, OptimizationLevel, ParseErrorType, Position,
+    Scope, AST,
+};
 use serde::{Deserialize, Serialize};
 
 pub mod abis;
 pub mod block_cache;
+pub mod local_store;
 pub mod modules;
+pub mod project;
+pub mod redact;
+pub mod schema_import;
+pub mod spkg;
 pub mod tasks;
+pub mod test_data;
+pub mod webhook;
 mod widgets;
 
-use modules::Module;
+use modules::{Module, ModuleSort, SourceMap};
+use project::ProjectAction;
+use redact::Redaction;
 use serde_json::Value;
 use substreams_sink_rust_lib::{start_stream, start_stream_channel, StreamConfig};
-use tasks::{GuiMessage, MessageKind, StreamMessages, WorkerMessage};
-use tokio::runtime::Runtime;
-use widgets::{module_panel::ModulePanel, panels::rust_view_ui, *};
+use tasks::{
+    BuildResult, Channels, DepStep, GuiMessage, Message, MessageKind, RunSummary, StreamMessages,
+    StreamProgress, WorkerMessage,
+};
+use tokio::{
+    runtime::Runtime,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedSender},
+        OwnedSemaphorePermit, Semaphore,
+    },
+    task,
+    time::timeout,
+};
+use webhook::{WebhookConfig, WebhookSink};
+use widgets::{
+    focus::{self, PanelFocus},
+    module_panel::{ModuleAction, ModulePanel},
+    panels::rust_view_ui,
+    *,
+};
+
+/// The name the app is run with, which is also where eframe keeps its data
+pub const APP_ID: &str = "Substreams Editor";
 
 /// Config for the editor
 #[derive(Serialize, Deserialize)]
 pub struct EditorConfig {
     module_name: String,
+    /// More output modules to stream after `module_name`
+    #[serde(default)]
+    extra_output_modules: Vec<String>,
     substream_package: String,
     substream_endpoint: String,
     stream_start_block: i64,
     stream_stop_block: u64,
+    /// Posts every streamed output to a url when set
+    #[serde(default)]
+    webhook: WebhookConfig,
+    /// The block type of the chain, which `BLOCK` inputs are
+    #[serde(default = "default_source_type")]
+    source_type: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -49,8 +97,88 @@ pub struct EditorViews {
     show_messages: bool,
     show_user_config: bool,
     show_block_cache: bool,
+    #[serde(default)]
+    module_sort: ModuleSort,
+    #[serde(default)]
+    show_compile_errors: bool,
+    /// Whether to show the welcome message when the editor starts
+    #[serde(default = "default_show_welcome")]
+    show_welcome_on_startup: bool,
+    #[serde(default)]
+    show_schema_import: bool,
+    #[serde(default)]
+    show_abis: bool,
+    #[serde(default)]
+    show_save_source: bool,
+    #[serde(default)]
+    show_project: bool,
+    /// Hides every message that isn't an error
+    #[serde(default)]
+    show_only_errors: bool,
+    /// How much the whole editor is scaled, between `MIN_ZOOM` and `MAX_ZOOM`
+    #[serde(default = "default_zoom")]
+    zoom: f32,
+    #[serde(default)]
+    theme: Theme,
+}
+
+/// The editor's color theme
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+}
+
+impl Theme {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Theme::Dark => "Dark",
+            Theme::Light => "Light",
+        }
+    }
+
+    pub fn visuals(&self) -> egui::Visuals {
+        match self {
+            Theme::Dark => egui::Visuals::dark(),
+            Theme::Light => egui::Visuals::light(),
+        }
+    }
 }
 
+fn default_source_type() -> String {
+    modules::DEFAULT_SOURCE_TYPE.to_string()
+}
+
+fn default_show_welcome() -> bool {
+    true
+}
+
+fn default_zoom() -> f32 {
+    1.0
+}
+
+pub const MIN_ZOOM: f32 = 0.5;
+pub const MAX_ZOOM: f32 = 3.0;
+
+/// Whether the generated source currently compiles
+#[derive(Default)]
+pub enum CompileStatus {
+    #[default]
+    Unknown,
+    Ok,
+    Error(String),
+}
+
+/// How long the source has to stay unchanged before it gets checked
+const CHECK_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// How often a running stream checks whether it was cancelled while waiting for output
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// How often a running stream reports its progress to the gui
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
 #[derive(Serialize, Deserialize)]
 pub struct Spkg {
     pub name: String,
@@ -71,6 +199,11 @@ impl Spkg {
             url: "https://spkg.io/streamingfast/ethereum-explorer-v0.1.2.spkg".to_string(),
         }
     }
+
+    /// Where the package is kept once it's been downloaded
+    pub fn local_path(&self) -> PathBuf {
+        spkg::cache_path(&self.url)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -93,6 +226,47 @@ impl Endpoint {
             url: "https://eth.substreams.pinax.network:443".to_string(),
         }
     }
+
+    /// Checks the url looks like a substreams endpoint, eg `https://mainnet.eth.streamingfast.io:443`
+    pub fn validate(&self) -> Result<(), String> {
+        Self::validate_url(&self.url)
+    }
+
+    pub fn validate_url(url: &str) -> Result<(), String> {
+        let parsed = reqwest::Url::parse(url.trim())
+            .map_err(|err| format!("Invalid endpoint url `{}`: {}", url, err))?;
+
+        if !matches!(parsed.scheme(), "https" | "grpc") {
+            return Err(format!(
+                "Invalid endpoint url `{}`: the scheme must be https or grpc, not {}",
+                url,
+                parsed.scheme()
+            ));
+        }
+
+        if parsed.host_str().is_none_or(str::is_empty) {
+            return Err(format!("Invalid endpoint url `{}`: missing a host", url));
+        }
+
+        // `Url::port` hides the scheme's default port, so look for it in the url itself
+        let authority = url
+            .trim()
+            .split("://")
+            .nth(1)
+            .and_then(|rest| rest.split('/').next())
+            .unwrap_or_default();
+        let has_port = authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()));
+        if !has_port {
+            return Err(format!(
+                "Invalid endpoint url `{}`: missing a port, eg `:443`",
+                url
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -103,7 +277,45 @@ pub struct UserConfig {
     endpoint_list: Vec<Endpoint>,
     selected_endpoint: usize,
 
-    selected_module: String,
+    /// How many recent eval results to keep inline for each module
+    #[serde(default = "default_results_per_module")]
+    results_per_module: usize,
+
+    /// The maximum function call depth for rhai, so runaway recursion errors instead of crashing
+    #[serde(default = "default_max_call_levels")]
+    max_call_levels: usize,
+
+    /// How long to wait for a stream or block fetch to connect, in seconds
+    #[serde(default = "default_stream_timeout_secs")]
+    stream_timeout_secs: u64,
+
+    /// How many messages to keep, older ones are dropped first
+    #[serde(default = "default_max_messages")]
+    max_messages: usize,
+
+    /// How many streams and block fetches can run at once, so the endpoint isn't overwhelmed
+    #[serde(default = "default_max_concurrent_streams")]
+    max_concurrent_streams: usize,
+}
+
+fn default_results_per_module() -> usize {
+    1
+}
+
+fn default_max_call_levels() -> usize {
+    64
+}
+
+fn default_stream_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_messages() -> usize {
+    1000
+}
+
+fn default_max_concurrent_streams() -> usize {
+    2
 }
 
 impl Default for UserConfig {
@@ -113,19 +325,85 @@ impl Default for UserConfig {
             selected_substream: 0,
             endpoint_list: vec![Endpoint::pinax_mainnet(), Endpoint::sf_mainnet()],
             selected_endpoint: 0,
-            selected_module: "graph_out".to_string(),
+            results_per_module: default_results_per_module(),
+            max_call_levels: default_max_call_levels(),
+            stream_timeout_secs: default_stream_timeout_secs(),
+            max_messages: default_max_messages(),
+            max_concurrent_streams: default_max_concurrent_streams(),
+        }
+    }
+}
+
+impl UserConfig {
+    /// Keeps the lists non empty and the selections in range, so indexing them can't panic
+    fn fix_selection(&mut self) {
+        if self.substream_list.is_empty() {
+            self.substream_list = UserConfig::default().substream_list;
+        }
+        if self.endpoint_list.is_empty() {
+            self.endpoint_list = UserConfig::default().endpoint_list;
         }
+        self.selected_substream = self.selected_substream.min(self.substream_list.len() - 1);
+        self.selected_endpoint = self.selected_endpoint.min(self.endpoint_list.len() - 1);
     }
+
+    /// The selected endpoint
+    pub fn endpoint(&mut self) -> &Endpoint {
+        self.fix_selection();
+        &self.endpoint_list[self.selected_endpoint]
+    }
+}
+
+enum ListEdit {
+    Add,
+    Remove,
+}
+
+/// Add and remove buttons for an editable list, removing is disabled for the last entry
+fn list_edit_buttons(ui: &mut Ui, len: usize) -> Option<ListEdit> {
+    ui.horizontal(|ui| {
+        let add = ui.small_button("Add").clicked();
+        let remove = ui
+            .add_enabled(len > 1, egui::Button::new("Remove").small())
+            .clicked();
+
+        if add {
+            Some(ListEdit::Add)
+        } else if remove {
+            Some(ListEdit::Remove)
+        } else {
+            None
+        }
+    })
+    .inner
+}
+
+/// Name and url fields for editing an entry
+fn name_url_fields(ui: &mut Ui, id: &str, name: &mut String, url: &mut String) {
+    egui::Grid::new(id).num_columns(2).show(ui, |ui| {
+        ui.label("Name");
+        ui.text_edit_singleline(name);
+        ui.end_row();
+        ui.label("URL");
+        ui.text_edit_singleline(url);
+        ui.end_row();
+    });
 }
 
 impl Widget for &mut UserConfig {
     fn ui(self, ui: &mut Ui) -> egui::Response {
+        self.fix_selection();
+
         let UserConfig {
             substream_list,
             selected_substream,
             endpoint_list,
             selected_endpoint,
-            selected_module,
+            results_per_module,
+            max_call_levels,
+            stream_timeout_secs,
+            max_messages,
+            max_concurrent_streams,
         } = self;
 
         ui.vertical(|ui| {
@@ -144,6 +422,24 @@ impl Widget for &mut UserConfig {
                         }
                     }
                 });
+            match list_edit_buttons(ui, substream_list.len()) {
+                Some(ListEdit::Add) => {
+                    substream_list.push(Spkg {
+                        name: "New Substream".to_string(),
+                        url: String::new(),
+                    });
+                    *selected_substream = substream_list.len() - 1;
+                }
+                Some(ListEdit::Remove) => {
+                    substream_list.remove(*selected_substream);
+                    *selected_substream = (*selected_substream).min(substream_list.len() - 1);
+                }
+                None => {}
+            }
+            ui.collapsing("Edit Substream", |ui| {
+                let Spkg { name, url } = &mut substream_list[*selected_substream];
+                name_url_fields(ui, "edit_substream", name, url);
+            });
 
             ui.label("Endpoint");
             ComboBox::from_label("Endpoint")
@@ -158,18 +454,79 @@ impl Widget for &mut UserConfig {
                         }
                     }
                 });
+            match list_edit_buttons(ui, endpoint_list.len()) {
+                Some(ListEdit::Add) => {
+                    endpoint_list.push(Endpoint {
+                        name: "New Endpoint".to_string(),
+                        url: String::new(),
+                    });
+                    *selected_endpoint = endpoint_list.len() - 1;
+                }
+                Some(ListEdit::Remove) => {
+                    endpoint_list.remove(*selected_endpoint);
+                    *selected_endpoint = (*selected_endpoint).min(endpoint_list.len() - 1);
+                }
+                None => {}
+            }
+            ui.collapsing("Edit Endpoint", |ui| {
+                let endpoint = &mut endpoint_list[*selected_endpoint];
+                name_url_fields(ui, "edit_endpoint", &mut endpoint.name, &mut endpoint.url);
+                if let Err(err) = endpoint.validate() {
+                    ui.colored_label(egui::Color32::RED, err);
+                }
+            });
+
+            ui.separator();
+            ui.label("Results kept per module");
+            ui.add(egui::Slider::new(results_per_module, 1..=20));
 
-            ui.label("Module Name");
-            ui.text_edit_singleline(selected_module);
+            ui.label("Max call depth");
+            ui.add(egui::Slider::new(max_call_levels, 8..=512));
+
+            ui.label("Stream connect timeout");
+            ui.add(egui::Slider::new(stream_timeout_secs, 5..=300).suffix("s"));
+
+            ui.label("Max messages");
+            ui.add(egui::Slider::new(max_messages, 100..=10000).logarithmic(true));
+
+            ui.label("Concurrent streams");
+            ui.add(egui::Slider::new(max_concurrent_streams, 1..=8))
+                .on_hover_text("Streams and block fetches past this wait for one to finish");
         })
         .response
     }
 }
 
+impl EditorConfig {
+    /// Checks the stream config can start a stream, so bad config gets a clear error instead of a failed stream
+    pub fn validate(&self) -> Result<(), String> {
+        Endpoint::validate_url(&self.substream_endpoint)?;
+
+        let start = self.stream_start_block;
+        let stop = self.stream_stop_block;
+        // A negative start is relative to the chain head, so there's no way to tell if an absolute stop is after it
+        if start < 0 && stop != 0 {
+            return Err(format!(
+                "Start block {} is relative to the chain head, so the stop block must be 0 (no stop block)",
+                start
+            ));
+        }
+        if start >= 0 && stop != 0 && stop <= start as u64 {
+            return Err(format!(
+                "Stop block {} must be after start block {}, or 0 to stream without stopping",
+                stop, start
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 impl Default for EditorConfig {
     fn default() -> Self {
         Self {
             module_name: "graph_out".to_string(),
+            extra_output_modules: Vec::new(),
             substream_endpoint: "https://mainnet.eth.streamingfast.io:443".to_string(),
             // Default to the Uniswap v3 substream package
             substream_package: "https://github.com/streamingfast/substreams-uniswap-v3/releases/download/v0.2.8/substreams.spkg".to_string(),
@@ -177,6 +534,8 @@ impl Default for EditorConfig {
             stream_start_block: 12369621,
             // Default to +10 blocks
             stream_stop_block: 12369631,
+            webhook: WebhookConfig::default(),
+            source_type: default_source_type(),
         }
     }
 }
@@ -191,6 +550,42 @@ impl Default for EditorViews {
             show_messages: true,
             show_user_config: false,
             show_block_cache: false,
+            module_sort: ModuleSort::default(),
+            show_compile_errors: false,
+            show_welcome_on_startup: default_show_welcome(),
+            show_schema_import: false,
+            show_abis: false,
+            show_save_source: false,
+            show_project: false,
+            show_only_errors: false,
+            zoom: default_zoom(),
+            theme: Theme::default(),
+        }
+    }
+}
+
+/// Picks an output module from the package's modules once they're known, otherwise it's typed in
+fn output_module_field(
+    ui: &mut Ui,
+    id: impl Hash,
+    module_name: &mut String,
+    known_modules: Option<&Vec<String>>,
+) {
+    match known_modules {
+        Some(names) => {
+            ComboBox::from_id_source(id)
+                .selected_text(module_name.as_str())
+                .show_ui(ui, |ui| {
+                    for name in names {
+                        ui.selectable_value(module_name, name.clone(), name);
+                    }
+                });
+        }
+        None => {
+            ui.add(
+                egui::TextEdit::singleline(module_name)
+                    .hint_text("Load the package to pick from its modules"),
+            );
         }
     }
 }
@@ -200,7 +595,14 @@ impl Default for EditorViews {
 pub struct EditorState {
     template_repo_path: String,
 
+    /// Path to the graphql schema to scaffold modules from
+    #[serde(default)]
+    schema_path: String,
+
     substreams_api_key: String,
+    /// Set once a key is saved from the user config window, so it's used over the `API_KEY` environment variable
+    #[serde(default)]
+    api_key_saved: bool,
 
     user_config: UserConfig,
 
@@ -213,231 +615,1167 @@ pub struct EditorState {
     /// A map from abi_name -> abj_json
     abis: HashMap<String, String>,
 
-    messages: Vec<MessageKind>,
+    /// The most recent messages, capped at `UserConfig::max_messages`
+    messages: VecDeque<Message>,
+    /// How many older messages were dropped to stay under the cap
+    #[serde(default)]
+    dropped_messages: usize,
     /// The search string for the messages
     message_search: String,
 
+    /// The report for the last completed stream, and where to save it
+    #[serde(skip)]
+    last_run_report: Option<String>,
+    #[serde(default)]
+    report_path: String,
+    /// Where Save Source last wrote the generated source
+    #[serde(default)]
+    source_path: String,
+    /// Where the project was last exported to or imported from
+    #[serde(default)]
+    project_path: String,
+    /// Where Export Log writes the messages
+    #[serde(default)]
+    log_path: String,
+    /// Whether the project changed since it was last exported or imported.
+    /// eframe's autosave doesn't clear it, since the autosave is what gets replaced
+    #[serde(default)]
+    dirty: bool,
+    /// A hash of the project from the last frame with input, to notice edits by
+    #[serde(skip)]
+    project_fingerprint: Option<u64>,
+    /// A project action waiting for the user to confirm discarding their changes
+    #[serde(skip)]
+    pending_project_action: Option<ProjectAction>,
+    /// What to mask in the displayed json messages
+    #[serde(skip)]
+    redaction: Redaction,
+
     modules: HashMap<i64, Module>,
 
+    /// The module names in each package, keyed by the package url or path
+    #[serde(default)]
+    package_modules: HashMap<String, Vec<String>>,
+
+    /// Stream outputs captured from the messages panel, which can be used as module inputs
+    #[serde(default)]
+    stored_outputs: BTreeMap<String, Value>,
+
+    /// The most recent eval results for each module, keyed by module id
+    #[serde(skip)]
+    module_results: HashMap<i64, VecDeque<Value>>,
+
+    /// Whether the welcome message is currently showing, set on startup from `show_welcome_on_startup`
+    #[serde(skip)]
     display_welcome_message: bool,
 
+    /// The panel keyboard focus was last moved to with F6
+    #[serde(skip)]
+    panel_focus: PanelFocus,
+
+    #[serde(skip)]
+    compile_status: CompileStatus,
+    /// The last source seen by `update`, and when it changed if it hasn't been checked yet
+    #[serde(skip)]
+    checked_source: String,
+    #[serde(skip)]
+    source_changed_at: Option<Instant>,
+    /// The errors from the last failed `source_file`, so they are only reported when they change
+    #[serde(skip)]
+    source_errors: Vec<String>,
+
     #[serde(skip)]
     gui_receiver: Option<mpsc::Receiver<GuiMessage>>,
     #[serde(skip)]
     gui_sender: Option<mpsc::Sender<GuiMessage>>,
 
     #[serde(skip)]
-    stream_sender: Option<mpsc::Sender<StreamMessages>>,
+    stream_sender: Option<UnboundedSender<StreamMessages>>,
+    /// How far each running stream has got, by task id
+    #[serde(skip)]
+    stream_progress: BTreeMap<u64, StreamProgress>,
+    /// The streams and block range fetches that can be stopped, by task id
+    #[serde(skip)]
+    running_streams: BTreeMap<u64, String>,
 
     #[serde(skip)]
     worker_sender: Option<mpsc::Sender<WorkerMessage>>,
 }
 
+/// Optimized ASTs keyed by a hash of their source, so unchanged code isn't recompiled
+#[derive(Default)]
+struct AstCache {
+    /// Each AST with how long it took to compile and optimize
+    asts: HashMap<u64, (AST, Duration)>,
+    /// The compile time the last build skipped, if it reused an AST
+    reused: Option<Duration>,
+}
+
+impl AstCache {
+    /// The key for the source run with the scope.
+    /// Optimizing folds the scope's constants into the AST, so they're part of the key along with the source
+    fn key(scope: &Scope, code: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        code.hash(&mut hasher);
+        for (name, constant, value) in scope.iter_raw() {
+            if constant {
+                name.hash(&mut hasher);
+                // Not every value can be hashed, but they can all be printed
+                format!("{:?}", value).hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+}
+
+/// How many compiled sources to keep before the cache is cleared
+const AST_CACHE_SIZE: usize = 32;
+
 fn build_and_run(
     engine: &mut Engine,
     scope: &mut Scope,
     code: &str,
     main_ast: &mut AST,
+    ast_cache: &mut AstCache,
 ) -> Result<Dynamic, Box<EvalAltResult>> {
-    engine
-        .compile_with_scope(&scope, code)
-        .map_err(Into::into)
-        .and_then(|r| {
-            let ast = engine.optimize_ast(scope, r, OptimizationLevel::Full);
+    let key = AstCache::key(scope, code);
+    ast_cache.reused = None;
+
+    let ast = match ast_cache.asts.get(&key) {
+        Some((ast, compile_time)) => {
+            ast_cache.reused = Some(*compile_time);
+            ast.clone()
+        }
+        None => {
+            let started = Instant::now();
+            let ast = engine.compile_with_scope(&scope, code)?;
+            let ast = engine.optimize_ast(scope, ast, OptimizationLevel::Full);
+            let compile_time = started.elapsed();
+
+            if ast_cache.asts.len() >= AST_CACHE_SIZE {
+                ast_cache.asts.clear();
+            }
+            ast_cache.asts.insert(key, (ast.clone(), compile_time));
+            ast
+        }
+    };
 
-            // Merge the AST into the main
-            *main_ast += ast;
+    // Merge the AST into the main
+    *main_ast += ast;
 
-            // Evaluate
-            engine.eval_ast_with_scope::<Dynamic>(scope, &main_ast)
-        })
+    // Evaluate
+    engine.eval_ast_with_scope::<Dynamic>(scope, &main_ast)
 }
 
-impl EditorState {
-    pub fn new(cc: &eframe::CreationContext<'_>, api_key: Option<String>) -> Self {
-        let mut state;
+/// Evaluates a whole generated source. It defines every function again,
+/// so it replaces what was evaluated before instead of stacking duplicates on top
+fn eval_fresh(
+    engine: &mut Engine,
+    scope: &mut Scope,
+    code: &str,
+    main_ast: &mut AST,
+    ast_cache: &mut AstCache,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    *main_ast = AST::empty();
+    build_and_run(engine, scope, code, main_ast, ast_cache)
+}
 
-        #[cfg(not(feature = "dev"))]
-        if let Some(storage) = cc.storage {
-            state = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
-        } else {
-            state = Self::default();
-        };
+/// Starts the thread that evaluates rhai for the gui, replying on `gui_sender`.
+/// It runs until the gui closes, which drops either end of its channels
+fn spawn_worker(
+    worker_rec: Receiver<WorkerMessage>,
+    gui_sender: Sender<GuiMessage>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let engine = Engine::new_raw();
+        let scope = Scope::new();
+        let mut main_ast = AST::empty();
+        let mut ast_cache = AstCache::default();
+        let (mut engine, mut scope) = rhai::packages::streamline::init_package(engine, scope);
+        engine.set_optimization_level(OptimizationLevel::Full);
+
+        let mut stored_outputs: HashMap<String, Dynamic> = HashMap::new();
+        // Parsed ABIs, kept in the scope as `ABIS` so handlers can read them
+        let mut abis = rhai::Map::new();
+        let abi_sources = AbiSources::default();
+        abis::decode::register(&mut engine, abi_sources.clone());
+        local_store::register(&mut engine);
+
+        // Blocks until there's work, and stops once the gui has closed
+        while let Ok(msg) = worker_rec.recv() {
+            let reply = match msg {
+                WorkerMessage::Eval(code) => {
+                    let result = build_and_run(
+                        &mut engine,
+                        &mut scope,
+                        &code,
+                        &mut main_ast,
+                        &mut ast_cache,
+                    );
+                    Some(eval_reply(result, None))
+                }
+                WorkerMessage::EvalFresh(code, source_map) => {
+                    let result = eval_fresh(
+                        &mut engine,
+                        &mut scope,
+                        &code,
+                        &mut main_ast,
+                        &mut ast_cache,
+                    );
+                    // Running unchanged source again is what the cache is for, so say what it saved
+                    if let (Ok(_), Some(compile_time)) = (&result, ast_cache.reused) {
+                        let message = format!(
+                            "Reused the compiled source, skipping {} ms of compiling",
+                            compile_time.as_millis()
+                        );
+                        let _ = gui_sender.send(GuiMessage::PushMessage(message));
+                    }
+                    Some(eval_reply(result, Some(&source_map)))
+                }
+                WorkerMessage::EvalWithArgs(module_name, fn_name, args, store) => {
+                    let args = args
+                        .into_iter()
+                        .filter_map(|v| serde_json::from_value(v).ok())
+                        .collect::<Vec<Dynamic>>();
 
-        #[cfg(feature = "dev")]
-        {
-            state = Self::default();
-        }
+                    let result =
+                        call_handler(&engine, &mut scope, &main_ast, &fn_name, args, store);
 
-        // Channel from: gui -> worker thread
-        let (worker_send, worker_rec) = mpsc::channel();
+                    Some(result_reply(result, module_name, fn_name))
+                }
+                WorkerMessage::StoreOutput(name, output) => {
+                    match serde_json::from_value::<Dynamic>(output) {
+                        Ok(output) => {
+                            stored_outputs.insert(name, output);
+                            None
+                        }
+                        Err(err) => Some(GuiMessage::PushError(format!(
+                            "Failed to store output `{}`: {}",
+                            name, err
+                        ))),
+                    }
+                }
+                WorkerMessage::EvalWithOutput(module_name, fn_name, output_name) => {
+                    match stored_outputs.get(&output_name) {
+                        Some(output) => {
+                            let result: Result<Dynamic, _> =
+                                engine.call_fn(&mut scope, &main_ast, &fn_name, (output.clone(),));
 
-        // Channel from: worker -> gui
-        let (gui_send, gui_rec) = mpsc::channel();
+                            Some(result_reply(result, module_name, fn_name))
+                        }
+                        None => Some(GuiMessage::PushError(format!(
+                            "No stored output named `{}`",
+                            output_name
+                        ))),
+                    }
+                }
+                WorkerMessage::EvalWithDeps(steps, block) => eval_with_deps(
+                    &engine,
+                    &mut scope,
+                    &main_ast,
+                    &stored_outputs,
+                    steps,
+                    block,
+                    &gui_sender,
+                ),
+                WorkerMessage::LoadAbi(name, json) => {
+                    match serde_json::from_str::<Dynamic>(&json) {
+                        Ok(abi) => {
+                            abi_sources.write().unwrap().insert(name.clone(), json);
+                            abis.insert(name.into(), abi);
+                            scope.set_or_push("ABIS", abis.clone());
+                            None
+                        }
+                        Err(err) => Some(GuiMessage::PushError(format!(
+                            "Failed to load ABI `{}`: {}",
+                            name, err
+                        ))),
+                    }
+                }
+                WorkerMessage::Reset => {
+                    scope.clear();
+                    // Drop the functions from earlier evals, so they can't be called after a reset
+                    main_ast = AST::empty();
+                    scope.push("ABIS", abis.clone());
+                    // Nothing from before the reset is run again, so its ASTs would only take up memory
+                    ast_cache.asts.clear();
+                    Some(GuiMessage::ClearMessages)
+                }
+                WorkerMessage::Check(code, source_map) => {
+                    let result = engine
+                        .compile_with_scope(&scope, &code)
+                        .map(|_| ())
+                        .map_err(|err| compile_error_message(&err.0, err.1, Some(&source_map)));
+                    Some(GuiMessage::CheckResult(result))
+                }
+                WorkerMessage::SetMaxCallLevels(levels) => {
+                    engine.set_max_call_levels(levels);
+                    None
+                }
+                WorkerMessage::Build => {
+                    let result = engine.eval_with_scope::<Dynamic>(&mut scope, "codegen()");
+                    Some(GuiMessage::PushBuild(build_result(result)))
+                }
+            };
 
-        // Channel from: gui -> stream thread
-        let (stream_send, stream_rec) = mpsc::channel();
+            // Sending only fails once the gui has closed, so there's nothing left to work for
+            if let Some(reply) = reply {
+                if gui_sender.send(reply).is_err() {
+                    eprintln!("Worker thread: the gui has closed, exiting");
+                    break;
+                }
+            }
+        }
+    })
+}
 
-        // sender to the worker thread
-        state.worker_sender = Some(worker_send);
+/// Drops the oldest messages past the cap, so a long session doesn't bloat the save file,
+/// counting them in `dropped` so the panel can say how many are hidden
+fn cap_messages(messages: &mut VecDeque<Message>, max: usize, dropped: &mut usize) {
+    while messages.len() > max {
+        messages.pop_front();
+        *dropped += 1;
+    }
+}
 
-        // receiver on the gui thread
-        state.gui_receiver = Some(gui_rec);
-        // sender to the gui thread
-        state.gui_sender = Some(gui_send.clone());
+/// Checks an api key was configured, since streams without one just fail to start with no reason given
+pub fn validate_api_key(api_key: &str) -> Result<(), String> {
+    if api_key.trim().is_empty() {
+        Err(
+            "No API key configured, set one in User Config or the API_KEY environment variable"
+                .to_string(),
+        )
+    } else {
+        Ok(())
+    }
+}
 
-        // sender to the stream thread
-        state.stream_sender = Some(stream_send);
+/// How many stream tasks can run at once, so the endpoint isn't overwhelmed
+///
+/// The limit can change while tasks hold permits. Lowering it forgets the free permits, and the rest
+/// are owed and forgotten as running tasks release theirs, so the count never goes over the new limit.
+#[derive(Clone)]
+struct StreamLimit {
+    semaphore: Arc<Semaphore>,
+    max: usize,
+    /// Permits to forget as they're released rather than give to the next task
+    owed: Arc<AtomicUsize>,
+}
 
-        state.modules = Module::build_default_modules();
+impl StreamLimit {
+    fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max,
+            owed: Arc::default(),
+        }
+    }
 
-        let mut abis = HashMap::new();
-        abis.insert("erc20".into(), abis::ERC20.to_string());
-        abis.insert("erc721".into(), abis::ERC721.to_string());
-        state.abis = abis;
+    fn set_max(&mut self, max: usize) {
+        let max = max.max(1);
+        if max > self.max {
+            // Permits still owed from lowering it before cancel out first
+            let raise = max - self.max;
+            let owed = self
+                .owed
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |owed| {
+                    Some(owed.saturating_sub(raise))
+                })
+                .unwrap();
+            self.semaphore.add_permits(raise - owed.min(raise));
+        } else {
+            let lower = self.max - max;
+            let forgotten = self.forget_free(lower);
+            self.owed.fetch_add(lower - forgotten, Ordering::SeqCst);
+        }
+        self.max = max;
+    }
 
-        state.display_welcome_message = true;
+    /// Forgets up to `n` of the permits no task holds, returning how many it forgot.
+    /// Tokio only has `Semaphore::forget_permits` from 1.37, so they're taken and forgotten instead
+    fn forget_free(&self, n: usize) -> usize {
+        let free = self.semaphore.available_permits().min(n);
+        match self.semaphore.try_acquire_many(free as u32) {
+            Ok(permits) => {
+                permits.forget();
+                free
+            }
+            // A task took some in the meantime, so they're owed instead
+            Err(_) => 0,
+        }
+    }
 
-        if let Some(api_key) = api_key {
-            state.substreams_api_key = api_key;
+    /// Hands a finished task's permit back, or forgets it if the limit was lowered below the running tasks
+    fn release(&self, permit: OwnedSemaphorePermit) {
+        let owed = self
+            .owed
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |owed| {
+                owed.checked_sub(1)
+            });
+        if owed.is_ok() {
+            permit.forget();
         }
+    }
+}
 
-        let gui_sender = gui_send.clone();
-        thread::spawn(move || {
-            let engine = Engine::new_raw();
-            let scope = Scope::new();
-            let mut main_ast = AST::empty();
-            let (mut engine, mut scope) = rhai::packages::streamline::init_package(engine, scope);
-            engine.set_optimization_level(OptimizationLevel::Full);
+/// A stream or block fetch running as its own task on the stream thread
+struct StreamTask {
+    /// Shown on the task's messages, so output from concurrent streams can be told apart
+    id: u64,
+    connect_timeout: Duration,
+    /// Set by a `Cancel` to stop this task, each task has its own so finishing one never affects the next
+    cancel: Arc<AtomicBool>,
+    gui_sender: Sender<GuiMessage>,
+}
 
-            // TODO Add support to store outputs of streams to use as input data
+impl StreamTask {
+    /// Sends the message tagged with the stream it came from
+    fn send(&self, message: GuiMessage) -> Result<(), SendError<GuiMessage>> {
+        let label = format!("stream {}", self.id);
+        self.gui_sender
+            .send(GuiMessage::Tagged(label, Box::new(message)))
+    }
 
-            loop {
-                while let Ok(msg) = worker_rec.try_recv() {
-                    match msg {
-                        WorkerMessage::Eval(code) => {
-                            let result =
-                                build_and_run(&mut engine, &mut scope, &code, &mut main_ast);
-                            let message = format!("Result: {:?}", result);
-                            gui_sender.send(GuiMessage::PushMessage(message)).unwrap()
-                        }
-                        WorkerMessage::EvalWithArgs(fn_name, args) => {
-                            let args = args
-                                .into_iter()
-                                .filter_map(|v| serde_json::from_value(v).ok())
-                                .collect::<Vec<Dynamic>>();
+    fn push_message(&self, message: impl fmt::Display) -> Result<(), SendError<GuiMessage>> {
+        self.send(GuiMessage::PushMessage(message.to_string()))
+    }
 
-                            let result: Result<Dynamic, _> =
-                                engine.call_fn(&mut scope, &main_ast, &fn_name, args);
-
-                            match result {
-                                Ok(result) => {
-                                    let result_json_str =
-                                        serde_json::to_string_pretty(&result).unwrap();
-                                    gui_sender
-                                        .send(GuiMessage::PushJson(result_json_str))
-                                        .unwrap()
-                                }
-                                Err(err) => {
-                                    let message = format!("Error: {:?}", err);
-                                    gui_sender.send(GuiMessage::PushMessage(message)).unwrap()
-                                }
-                            }
-                        }
-                        WorkerMessage::Reset => {
-                            gui_sender.send(GuiMessage::ClearMessages).unwrap();
-                            scope.clear();
-                        }
-                        WorkerMessage::Build => {
-                            let result = engine.eval_with_scope::<Dynamic>(&mut scope, "codegen()");
-                            gui_sender
-                                .send(GuiMessage::PushMessage(format!("Build Log: {:?}", result)))
-                                .unwrap();
-                        }
-                    };
-                }
+    fn push_error(&self, message: impl fmt::Display) -> Result<(), SendError<GuiMessage>> {
+        self.send(GuiMessage::PushError(message.to_string()))
+    }
+
+    /// The local path of a package, downloading it into the cache the first time a url is used
+    ///
+    /// Download failures are reported to the gui and give `None`.
+    async fn ensure_downloaded(
+        &self,
+        package_file: &str,
+    ) -> Result<Option<String>, SendError<GuiMessage>> {
+        if !spkg::is_url(package_file) {
+            return Ok(Some(package_file.to_string()));
+        }
+
+        let path = spkg::cache_path(package_file);
+        if !path.exists() {
+            self.push_message(format!("Downloading {}", package_file))?;
+            if let Err(err) = spkg::download(package_file).await {
+                self.push_error(err)?;
+                return Ok(None);
             }
-        });
+            self.push_message(format!("Downloaded {} to {}", package_file, path.display()))?;
+        }
+        Ok(Some(path.display().to_string()))
+    }
 
-        let gui_sender = gui_send.clone();
-        thread::spawn(move || {
-            let rt = Runtime::new().expect("Unable to create Runtime");
-            let _enter = rt.enter();
-            rt.block_on(async move {
-                loop {
-                    while let Ok(msg) = stream_rec.try_recv() {
-                        match msg {
-                            StreamMessages::Run {
-                                start,
-                                stop,
-                                api_key,
-                                package_file,
-                                endpoint,
-                                module_name,
-                            } => {
-                                let stream_config = StreamConfig {
-                                    endpoint_url: endpoint,
-                                    package_file,
-                                    module_name,
-                                    token: Some(api_key),
-                                    start,
-                                    stop,
-                                };
+    /// Runs a `Run`, `GetBlock`, `GetBlockRange`, `GetLatestBlock` or `ListModules`, the other messages are handled by the stream thread itself
+    async fn handle(&self, msg: StreamMessages) -> Result<(), SendError<GuiMessage>> {
+        let gui_sender = &self.gui_sender;
+        let connect_timeout = self.connect_timeout;
+
+        match msg {
+            StreamMessages::Run {
+                start,
+                stop,
+                api_key,
+                package_file,
+                endpoint,
+                module_names,
+                webhook,
+            } => {
+                if let Err(err) = validate_api_key(&api_key) {
+                    return self.push_error(err);
+                }
+                let Some(local_package) = self.ensure_downloaded(&package_file).await? else {
+                    return Ok(());
+                };
+
+                // The sink streams a single output module, so multiple modules run one after another
+                let tag_outputs = module_names.len() > 1;
+                let mut cancelled = false;
+                for module_name in module_names {
+                    if cancelled {
+                        break;
+                    }
 
-                                let start_message =
-                                    format!("Starting stream from {} to {}", start, stop);
+                    if tag_outputs {
+                        self.push_message(format!("Output from `{}`", module_name))?;
+                    }
 
-                                if let Ok(rx) = start_stream_channel(stream_config).await {
-                                    gui_sender
-                                        .send(GuiMessage::PushMessage(start_message))
-                                        .unwrap();
-                                    while let Ok(data) = rx.recv() {
-                                        gui_sender.send(GuiMessage::PushJson(data)).unwrap();
-                                    }
-                                } else {
-                                    let message = "Failed to start stream".to_string();
-                                    gui_sender.send(GuiMessage::PushMessage(message)).unwrap();
-                                }
+                    let mut summary = RunSummary {
+                        module_name: module_name.clone(),
+                        package_file: package_file.clone(),
+                        endpoint: endpoint.clone(),
+                        start,
+                        stop,
+                        outputs: 0,
+                        errors: 0,
+                        last_block: None,
+                        duration: Duration::ZERO,
+                    };
 
-                                let stop_message = "Stream Completed Successfully".to_string();
-                                gui_sender
-                                    .send(GuiMessage::PushMessage(stop_message))
-                                    .unwrap();
-                            }
-                            StreamMessages::GetBlock {
-                                number,
-                                api_key,
-                                endpoint,
-                                cache_slot,
-                            } => {
-                                let spkg = Spkg::eth_explorer().url;
-
-                                let stream_config = StreamConfig {
-                                    endpoint_url: endpoint,
-                                    package_file: spkg,
-                                    module_name: "map_block_full".to_string(),
-                                    token: Some(api_key),
-                                    start: number,
-                                    stop: (number + 1) as u64,
-                                };
+                    let stream_config = StreamConfig {
+                        endpoint_url: endpoint.clone(),
+                        package_file: local_package.clone(),
+                        module_name,
+                        token: Some(api_key.clone()),
+                        start,
+                        stop,
+                    };
 
-                                let start_message = format!("Getting block {}", number);
+                    let start_message = if stop == 0 {
+                        format!("Streaming live from block {}", start)
+                    } else {
+                        format!("Starting stream from {} to {}", start, stop)
+                    };
 
-                                if let Ok(rx) = start_stream_channel(stream_config).await {
-                                    gui_sender
-                                        .send(GuiMessage::PushMessage(start_message))
-                                        .unwrap();
-                                    while let Ok(data) = rx.recv() {
-                                        gui_sender
-                                            .send(GuiMessage::SetBlock(cache_slot, data))
-                                            .unwrap();
-                                    }
-                                } else {
-                                    let message = "Failed to get block".to_string();
-                                    gui_sender.send(GuiMessage::PushMessage(message)).unwrap();
+                    let started_at = Instant::now();
+                    // The error is turned into a string right away, since it can't be held across an await
+                    let connect = timeout(connect_timeout, start_stream_channel(stream_config))
+                        .await
+                        .map(|result| result.map_err(|err| err.to_string()));
+                    if let Ok(Ok(rx)) = connect {
+                        self.push_message(start_message)?;
+                        let sink = webhook
+                            .as_ref()
+                            .map(|config| WebhookSink::new(config, gui_sender.clone()));
+                        let mut progress = StreamProgress {
+                            start,
+                            stop,
+                            block: None,
+                            outputs: 0,
+                        };
+                        let mut progress_sent_at = Instant::now();
+                        self.send(GuiMessage::SetProgress(self.id, Some(progress.clone())))?;
+                        loop {
+                            // Dropping the receiver on cancel ends the stream, so the next run starts clean
+                            if self.cancel.load(Ordering::Relaxed) {
+                                cancelled = true;
+                                break;
+                            }
+                            // The receiver blocks, so let the runtime move other tasks off this thread meanwhile
+                            let data = match task::block_in_place(|| rx.recv_timeout(CANCEL_POLL)) {
+                                Ok(data) => data,
+                                Err(RecvTimeoutError::Timeout) => continue,
+                                Err(RecvTimeoutError::Disconnected) => break,
+                            };
+                            summary.outputs += 1;
+                            // Parsed once here, so the gui gets the value without parsing it again
+                            let json = serde_json::from_str::<Value>(&data);
+                            if let Some(block) =
+                                json.as_ref().ok().and_then(block_cache::block_number)
+                            {
+                                summary.last_block = Some(block);
+                            }
+                            // Throttled, so the gui isn't asked to repaint for every output
+                            if progress_sent_at.elapsed() >= PROGRESS_INTERVAL {
+                                progress_sent_at = Instant::now();
+                                progress.outputs = summary.outputs;
+                                progress.block = summary.last_block;
+                                self.send(GuiMessage::SetProgress(
+                                    self.id,
+                                    Some(progress.clone()),
+                                ))?;
+                            }
+                            if let Some(sink) = &sink {
+                                sink.send(data.clone()).await;
+                            }
+                            match json {
+                                Ok(json) => self.send(GuiMessage::PushJson(json))?,
+                                Err(err) => {
+                                    summary.errors += 1;
+                                    self.push_error(invalid_json_message(
+                                        "The stream sent invalid json",
+                                        &err,
+                                        &data,
+                                    ))?
                                 }
                             }
                         }
+                        if let Some(sink) = sink {
+                            let totals = sink.finish().await;
+                            summary.errors += totals.failed;
+                            self.push_message(totals.to_string())?;
+                        }
+
+                        summary.duration = started_at.elapsed();
+                        self.send(GuiMessage::RunReport(summary.to_string()))?;
+
+                        let outcome = if cancelled { "Cancelled" } else { "Completed" };
+                        self.push_message(format!(
+                            "{}: {} outputs in {:.1}s",
+                            outcome,
+                            summary.outputs,
+                            summary.duration.as_secs_f64()
+                        ))?;
+                    } else if let Ok(Err(err)) = connect {
+                        self.push_error(format!("Failed to start stream: {}", err))?;
+                    } else {
+                        self.push_error(format!(
+                            "Error: stream didn't connect within {}s",
+                            connect_timeout.as_secs()
+                        ))?;
+                    }
+                }
+                self.send(GuiMessage::SetProgress(self.id, None))?;
+            }
+            StreamMessages::GetBlock {
+                number,
+                api_key,
+                endpoint,
+                cache_slot,
+            } => {
+                if let Err(err) = validate_api_key(&api_key) {
+                    return self.push_error(err);
+                }
+
+                let Some(spkg) = self.ensure_downloaded(&Spkg::eth_explorer().url).await? else {
+                    return Ok(());
+                };
+
+                let stream_config = StreamConfig {
+                    endpoint_url: endpoint,
+                    package_file: spkg,
+                    module_name: "map_block_full".to_string(),
+                    token: Some(api_key),
+                    start: number,
+                    stop: (number + 1) as u64,
+                };
+
+                // The error is turned into a string right away, since it can't be held across an await
+                let connect = timeout(connect_timeout, start_stream_channel(stream_config))
+                    .await
+                    .map(|result| result.map_err(|err| err.to_string()));
+                if let Ok(Ok(rx)) = connect {
+                    self.push_message(format!("Getting block {}", number))?;
+                    while let Ok(data) = task::block_in_place(|| rx.recv()) {
+                        self.send(GuiMessage::SetBlock(cache_slot, data))?;
+                    }
+                } else if let Ok(Err(err)) = connect {
+                    self.push_error(format!("Failed to get block: {}", err))?;
+                } else {
+                    self.push_error(format!(
+                        "Error: block {} fetch didn't connect within {}s",
+                        number,
+                        connect_timeout.as_secs()
+                    ))?;
+                }
+            }
+            StreamMessages::GetBlockRange {
+                start,
+                count,
+                api_key,
+                endpoint,
+                first_slot,
+            } => {
+                if let Err(err) = validate_api_key(&api_key) {
+                    return self.push_error(err);
+                }
+                let Some(spkg) = self.ensure_downloaded(&Spkg::eth_explorer().url).await? else {
+                    return Ok(());
+                };
+
+                let stop = start as u64 + count;
+                let stream_config = StreamConfig {
+                    endpoint_url: endpoint,
+                    package_file: spkg,
+                    module_name: "map_block_full".to_string(),
+                    token: Some(api_key),
+                    start,
+                    stop,
+                };
+
+                // The error is turned into a string right away, since it can't be held across an await
+                let connect = timeout(connect_timeout, start_stream_channel(stream_config))
+                    .await
+                    .map(|result| result.map_err(|err| err.to_string()));
+                if let Ok(Ok(rx)) = connect {
+                    self.push_message(format!("Getting blocks {} to {}", start, stop - 1))?;
+                    // Never writes past the slots asked for, even if the stream sends extra outputs
+                    let slots = first_slot..first_slot + count as usize;
+                    for slot in slots {
+                        if self.cancel.load(Ordering::Relaxed) {
+                            self.push_message("Block fetch cancelled")?;
+                            break;
+                        }
+                        let Ok(data) = task::block_in_place(|| rx.recv()) else {
+                            break;
+                        };
+                        self.send(GuiMessage::SetBlock(slot, data))?;
+                    }
+                } else if let Ok(Err(err)) = connect {
+                    self.push_error(format!("Failed to get blocks: {}", err))?;
+                } else {
+                    self.push_error(format!(
+                        "Error: blocks {} to {} fetch didn't connect within {}s",
+                        start,
+                        stop - 1,
+                        connect_timeout.as_secs()
+                    ))?;
+                }
+            }
+            StreamMessages::GetLatestBlock { api_key, endpoint } => {
+                if let Err(err) = validate_api_key(&api_key) {
+                    return self.push_error(err);
+                }
+                let Some(spkg) = self.ensure_downloaded(&Spkg::eth_explorer().url).await? else {
+                    return Ok(());
+                };
+
+                // A negative start is relative to the chain head, so the first block streamed is the head
+                let stream_config = StreamConfig {
+                    endpoint_url: endpoint,
+                    package_file: spkg,
+                    module_name: "map_block_full".to_string(),
+                    token: Some(api_key),
+                    start: -1,
+                    stop: 0,
+                };
+
+                // The error is turned into a string right away, since it can't be held across an await
+                let connect = timeout(connect_timeout, start_stream_channel(stream_config))
+                    .await
+                    .map(|result| result.map_err(|err| err.to_string()));
+                match connect {
+                    Ok(Ok(rx)) => {
+                        // Only the first block is needed, dropping the receiver after it ends the stream
+                        let number = task::block_in_place(|| rx.recv())
+                            .ok()
+                            .and_then(|data| serde_json::from_str::<Value>(&data).ok())
+                            .as_ref()
+                            .and_then(block_cache::block_number);
+                        match number {
+                            Some(number) => {
+                                self.push_message(format!("Latest block is {}", number))?;
+                                self.send(GuiMessage::SetLatestBlock(number))?;
+                            }
+                            None => self.push_error("Failed to get the latest block number")?,
+                        }
+                    }
+                    Ok(Err(err)) => {
+                        self.push_error(format!("Failed to get the latest block: {}", err))?
+                    }
+                    Err(_) => self.push_error(format!(
+                        "Error: latest block fetch didn't connect within {}s",
+                        connect_timeout.as_secs()
+                    ))?,
+                }
+            }
+            StreamMessages::ListModules { package_file } => {
+                let Some(local_package) = self.ensure_downloaded(&package_file).await? else {
+                    return Ok(());
+                };
+                let names =
+                    spkg::read(&local_package).and_then(|package| spkg::module_names(&package));
+                match names {
+                    Ok(names) => {
+                        self.push_message(format!(
+                            "Found {} modules in {}",
+                            names.len(),
+                            package_file
+                        ))?;
+                        self.send(GuiMessage::SetPackageModules(package_file, names))?;
+                    }
+                    Err(err) => self.push_error(err)?,
+                }
+            }
+            StreamMessages::SetTimeout(_)
+            | StreamMessages::SetConcurrency(_)
+            | StreamMessages::Cancel(_) => {}
+        }
+        Ok(())
+    }
+}
+
+/// The message for the result of evaluating some code
+fn eval_reply(
+    result: Result<Dynamic, Box<EvalAltResult>>,
+    source_map: Option<&SourceMap>,
+) -> GuiMessage {
+    match result {
+        Ok(result) => GuiMessage::PushMessage(format!("Result: {:?}", result)),
+        Err(err) => GuiMessage::PushError(error_message(&err, source_map)),
+    }
+}
+
+/// How much of the offending data an invalid json error quotes
+const INVALID_JSON_SNIPPET: usize = 120;
+
+/// Describes a json parse failure, quoting the start of the data so the problem can be found
+fn invalid_json_message(context: &str, err: &serde_json::Error, data: &str) -> String {
+    let snippet: String = data.chars().take(INVALID_JSON_SNIPPET).collect();
+    let ellipsis = if snippet.len() < data.len() {
+        "…"
+    } else {
+        ""
+    };
+    format!("{}: {}\n{}{}", context, err, snippet, ellipsis)
+}
+
+/// Parses a fetched block into the cache slot, returning the block or the error message to show.
+/// Invalid json leaves the slot with what it had, rather than something that isn't a block
+fn set_block(
+    block_cache: &mut BlockCache,
+    slot: usize,
+    json_str: &str,
+) -> Result<Value, MessageKind> {
+    match serde_json::from_str::<Value>(json_str) {
+        Ok(value) => {
+            block_cache.set(slot, value.clone());
+            Ok(value)
+        }
+        Err(err) => {
+            let context = format!("The block for slot {} isn't valid json", slot);
+            let message = invalid_json_message(&context, &err, json_str);
+            Err(MessageKind::ErrorMessage(message))
+        }
+    }
+}
+
+/// The reply to calling a module's handler, its result as json or the error
+fn result_reply(
+    result: Result<Dynamic, Box<EvalAltResult>>,
+    module_name: String,
+    fn_name: String,
+) -> GuiMessage {
+    match result.map(|result| serde_json::to_value(&result)) {
+        Ok(Ok(value)) => GuiMessage::PushResult(module_name, fn_name, value),
+        Ok(Err(err)) => GuiMessage::PushError(format!(
+            "The result of `{}` can't be shown as json: {}",
+            fn_name, err
+        )),
+        Err(err) => GuiMessage::PushError(error_message(&err, None)),
+    }
+}
+
+/// Calls the handler with the args, returning its result.
+/// A store is passed a fresh local store after the args, and what it wrote there is its output
+fn call_handler(
+    engine: &Engine,
+    scope: &mut Scope,
+    ast: &AST,
+    handler: &str,
+    mut args: Vec<Dynamic>,
+    store: bool,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    if !store {
+        return engine.call_fn(scope, ast, handler, args);
+    }
+
+    let store = LocalStore::default();
+    args.push(Dynamic::from(store.clone()));
+    // Like a substreams store handler, whatever it returns is ignored
+    let _: Dynamic = engine.call_fn(scope, ast, handler, args)?;
+    Ok(Dynamic::from_map(store.to_map()))
+}
+
+/// Runs each step's handler on the block in order, with the outputs of earlier steps as its inputs
+///
+/// Every output but the last is pushed as it's made, so the whole chain can be followed in the messages.
+/// Inputs that aren't in the chain fall back to the stored outputs. The reply is the last step's result,
+/// or an error naming the step that failed.
+fn eval_with_deps(
+    engine: &Engine,
+    scope: &mut Scope,
+    ast: &AST,
+    stored_outputs: &HashMap<String, Dynamic>,
+    steps: Vec<DepStep>,
+    block: Value,
+    gui_sender: &Sender<GuiMessage>,
+) -> Option<GuiMessage> {
+    let block: Dynamic = match serde_json::from_value(block) {
+        Ok(block) => block,
+        Err(err) => {
+            return Some(GuiMessage::PushError(format!(
+                "Failed to read the block: {}",
+                err
+            )))
+        }
+    };
+
+    let mut outputs: HashMap<String, Dynamic> = HashMap::new();
+    let last = steps.len().saturating_sub(1);
+    for (i, step) in steps.into_iter().enumerate() {
+        let mut args = Vec::with_capacity(step.inputs.len() + 1);
+        for input in &step.inputs {
+            let arg = if input == "BLOCK" {
+                Some(block.clone())
+            } else if input == "PARAMS" {
+                Some(Dynamic::from(step.params.clone()))
+            } else {
+                outputs
+                    .get(input)
+                    .or_else(|| stored_outputs.get(input))
+                    .cloned()
+            };
+            match arg {
+                Some(arg) => args.push(arg),
+                None => {
+                    return Some(GuiMessage::PushError(format!(
+                        "Can't run `{}`, nothing has output its input `{}`",
+                        step.name, input
+                    )))
+                }
+            }
+        }
+
+        let output = match call_handler(engine, scope, ast, &step.handler, args, step.store) {
+            Ok(output) => output,
+            Err(err) => {
+                return Some(GuiMessage::PushError(format!(
+                    "Failed to run `{}`: {}",
+                    step.name,
+                    error_message(&err, None)
+                )))
+            }
+        };
+
+        let reply = result_reply(Ok(output.clone()), step.name.clone(), step.handler);
+        if i == last {
+            return Some(reply);
+        }
+        // A closed gui is noticed by the worker loop once this returns
+        let _ = gui_sender.send(reply);
+        outputs.insert(step.name, output);
+    }
+
+    None
+}
+
+/// The names and handlers of the map modules, sorted by name, for picking one to run on a block
+fn map_modules(modules: &HashMap<i64, Module>) -> Vec<(String, String)> {
+    let mut map_modules: Vec<(String, String)> = modules
+        .values()
+        .filter(|module| matches!(module, Module::Map { .. }))
+        .map(|module| (module.name().to_string(), module.handler().to_string()))
+        .collect();
+    map_modules.sort();
+    map_modules
+}
+
+/// Reads the outcome of `codegen()`, the build function the substreams runtime registers
+///
+/// `codegen()` comes from the rhai fork rather than this crate, so its result is read loosely: a string
+/// is taken as the path of the generated package, and a map can give a `path` and a `success` flag.
+/// Anything else is kept as the log of a build with no known output. A path is only kept if it
+/// exists, so the build folder button never opens a bad path.
+fn build_result(result: Result<Dynamic, Box<EvalAltResult>>) -> BuildResult {
+    let value = match result {
+        Ok(value) => value,
+        Err(err) => {
+            return BuildResult {
+                success: false,
+                path: None,
+                log: error_message(&err, None),
+            }
+        }
+    };
+
+    let (success, path) = if let Some(path) = value.clone().try_cast::<String>() {
+        (true, Some(path))
+    } else if let Some(map) = value.clone().try_cast::<rhai::Map>() {
+        let success = map.get("success").and_then(|s| s.as_bool().ok());
+        let path = map.get("path").and_then(|p| p.clone().try_cast::<String>());
+        (success.unwrap_or(true), path)
+    } else {
+        (true, None)
+    };
+
+    BuildResult {
+        success,
+        path: path.map(PathBuf::from).filter(|path| path.exists()),
+        log: value.to_string(),
+    }
+}
+
+/// Returns true if the error was caused by hitting the max call depth, even when nested inside a function call
+fn is_stack_overflow(err: &EvalAltResult) -> bool {
+    match err {
+        EvalAltResult::ErrorStackOverflow(_) => true,
+        EvalAltResult::ErrorInFunctionCall(_, _, inner, _) => is_stack_overflow(inner),
+        EvalAltResult::ErrorInModule(_, inner, _) => is_stack_overflow(inner),
+        _ => false,
+    }
+}
+
+/// Formats an eval error, adding a hint for stack overflows and the source position for compile errors
+///
+/// Pass the source map of generated source so compile errors in it can name their module.
+fn error_message(err: &EvalAltResult, source_map: Option<&SourceMap>) -> String {
+    if let EvalAltResult::ErrorParsing(kind, pos) = err {
+        compile_error_message(kind, *pos, source_map)
+    } else if is_stack_overflow(err) {
+        format!(
+            "Error: {:?}\nThe max call depth was reached. Try reducing the recursion in your handler, or raise the limit in the User Config.",
+            err
+        )
+    } else {
+        format!("Error: {:?}", err)
+    }
+}
+
+/// Formats a compile error with its line and column, and the module it's in when that can be found
+fn compile_error_message(
+    kind: &ParseErrorType,
+    pos: Position,
+    source_map: Option<&SourceMap>,
+) -> String {
+    let location = match (pos.line(), pos.position()) {
+        (Some(line), Some(col)) => format!(" at line {}, col {}", line, col),
+        (Some(line), None) => format!(" at line {}", line),
+        _ => String::new(),
+    };
+
+    let module = pos
+        .line()
+        .zip(source_map)
+        .and_then(|(line, source_map)| source_map.module_at(line));
+
+    match module {
+        Some(module) => format!("Compile error in `{}`{}: {}", module, location, kind),
+        None => format!("Compile error{}: {}", location, kind),
+    }
+}
+
+impl EditorState {
+    pub fn new(cc: &eframe::CreationContext<'_>, api_key: Option<String>) -> Self {
+        let mut state;
+
+        #[cfg(not(feature = "dev"))]
+        if let Some(storage) = cc.storage {
+            state = eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+        } else {
+            state = Self::default();
+        };
+
+        #[cfg(feature = "dev")]
+        {
+            state = Self::default();
+        }
+
+        // Channel from: gui -> worker thread
+        let (worker_send, worker_rec) = mpsc::channel();
+
+        for (name, output) in state.stored_outputs.iter() {
+            worker_send
+                .send(WorkerMessage::StoreOutput(name.clone(), output.clone()))
+                .unwrap();
+        }
+
+        // Channel from: worker -> gui
+        let (gui_send, gui_rec) = mpsc::channel();
+
+        // Channel from: gui -> stream thread
+        // A tokio channel, so the stream thread can await messages instead of polling
+        let (stream_send, mut stream_rec) = unbounded_channel();
+
+        // sender to the worker thread
+        state.worker_sender = Some(worker_send);
+
+        // receiver on the gui thread
+        state.gui_receiver = Some(gui_rec);
+        // sender to the gui thread
+        state.gui_sender = Some(gui_send.clone());
+
+        // sender to the stream thread
+        state.stream_sender = Some(stream_send);
+
+        // The saved modules are kept, the defaults are only there to start a new project from
+        if state.modules.is_empty() {
+            state.modules = Module::build_default_modules();
+        }
+
+        // Keep the ABIs the user loaded, and make sure the built in ones are there
+        state.abis.insert("erc20".into(), abis::ERC20.to_string());
+        state.abis.insert("erc721".into(), abis::ERC721.to_string());
+        state
+            .abis
+            .insert("erc1155".into(), abis::ERC1155.to_string());
+        state.abis.insert("weth".into(), abis::WETH.to_string());
+        state
+            .abis
+            .insert("uniswap_v3_pool".into(), abis::UNISWAP_V3_POOL.to_string());
+
+        // Apply the saved engine limits and ABIs before anything gets evaluated
+        state.sync_threads();
+
+        state.display_welcome_message = state.view_config.show_welcome_on_startup;
+
+        state.view_config.zoom = state.view_config.zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+        cc.egui_ctx.set_zoom_factor(state.view_config.zoom);
+
+        if let Some(api_key) = api_key.filter(|_| !state.api_key_saved) {
+            state.substreams_api_key = api_key;
+        }
+
+        spawn_worker(worker_rec, gui_send.clone());
+
+        let gui_sender = gui_send.clone();
+        thread::spawn(move || {
+            let rt = Runtime::new().expect("Unable to create Runtime");
+            let _enter = rt.enter();
+            rt.block_on(async move {
+                // A bad endpoint can otherwise hang the connect forever, blocking the task
+                let mut connect_timeout = Duration::from_secs(default_stream_timeout_secs());
+                let mut limit = StreamLimit::new(default_max_concurrent_streams());
+                let mut next_id = 0;
+                // The cancel flags of the tasks that haven't finished, removed by each task as it ends
+                let cancels: Arc<Mutex<HashMap<u64, Arc<AtomicBool>>>> = Arc::default();
+                // Ends once the gui has closed, which drops the runtime and any running tasks with it
+                while let Some(msg) = stream_rec.recv().await {
+                    match msg {
+                        StreamMessages::SetTimeout(duration) => connect_timeout = duration,
+                        StreamMessages::SetConcurrency(max) => limit.set_max(max),
+                        StreamMessages::Cancel(id) => {
+                            let cancels = cancels.lock().unwrap();
+                            let stopped = cancels
+                                .iter()
+                                .filter(|(other, _)| id.is_none_or(|id| **other == id));
+                            for (_, cancel) in stopped {
+                                cancel.store(true, Ordering::Relaxed);
+                            }
+                        }
+                        // Each stream gets its own task, so a long run doesn't hold up a block fetch
+                        msg => {
+                            next_id += 1;
+                            let cancel = Arc::new(AtomicBool::new(false));
+                            cancels.lock().unwrap().insert(next_id, cancel.clone());
+                            let task = StreamTask {
+                                id: next_id,
+                                connect_timeout,
+                                cancel,
+                                gui_sender: gui_sender.clone(),
+                            };
+                            // Listed from before the task gets its turn, so a queued task can be stopped too
+                            let running_label = msg.running_label();
+                            if let Some(label) = &running_label {
+                                let message = GuiMessage::SetRunning(task.id, Some(label.clone()));
+                                if gui_sender.send(message).is_err() {
+                                    break;
+                                }
+                            }
+                            let limit = limit.clone();
+                            let cancels = cancels.clone();
+                            tokio::spawn(async move {
+                                let permit = limit
+                                    .semaphore
+                                    .clone()
+                                    .acquire_owned()
+                                    .await
+                                    .expect("Stream limit semaphore closed");
+
+                                // Sends only fail once the gui has closed, so there's nowhere to stream to
+                                if task.handle(msg).await.is_err() {
+                                    eprintln!("Stream {}: the gui has closed, stopping", task.id);
+                                }
+                                cancels.lock().unwrap().remove(&task.id);
+                                limit.release(permit);
+                                if running_label.is_some() {
+                                    let _ =
+                                        task.gui_sender.send(GuiMessage::SetRunning(task.id, None));
+                                }
+                            });
+                        }
                     }
                 }
             })
@@ -446,16 +1784,263 @@ impl EditorState {
         state
     }
 
-    pub fn source_file(&self) -> String {
+    /// Sends the config the worker and stream threads keep their own copy of, after it was loaded or replaced
+    fn sync_threads(&self) {
+        if let Some(worker_sender) = &self.worker_sender {
+            let message = WorkerMessage::SetMaxCallLevels(self.user_config.max_call_levels);
+            worker_sender.send(message).unwrap();
+
+            for (name, json) in self.abis.iter() {
+                let message = WorkerMessage::LoadAbi(name.clone(), json.clone());
+                worker_sender.send(message).unwrap();
+            }
+        }
+
+        if let Some(stream_sender) = &self.stream_sender {
+            let duration = Duration::from_secs(self.user_config.stream_timeout_secs);
+            stream_sender
+                .send(StreamMessages::SetTimeout(duration))
+                .unwrap();
+
+            let limit = self.user_config.max_concurrent_streams;
+            stream_sender
+                .send(StreamMessages::SetConcurrency(limit))
+                .unwrap();
+        }
+    }
+
+    /// A hash of what a project file saves, besides the block cache and the ABIs which are too big to hash.
+    /// Loading an ABI marks the project dirty itself
+    fn fingerprint(&self) -> u64 {
+        let mut ids = self.modules.keys().collect::<Vec<_>>();
+        ids.sort();
+        let modules = ids.iter().map(|id| &self.modules[id]).collect::<Vec<_>>();
+
+        let project = (modules, &self.user_config, &self.editor_config);
+        let json = serde_json::to_string(&project).unwrap_or_default();
+        let mut hasher = DefaultHasher::new();
+        json.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Marks the project dirty if it changed since the last call.
+    /// Edits come from input, so frames without any are skipped instead of hashing the project every frame
+    fn track_changes(&mut self, ctx: &Context) {
+        let had_input = ctx.input(|i| {
+            i.pointer.any_down()
+                || i.events
+                    .iter()
+                    .any(|event| !matches!(event, egui::Event::PointerMoved(_)))
+        });
+        if !had_input && self.project_fingerprint.is_some() {
+            return;
+        }
+
+        let fingerprint = self.fingerprint();
+        if self
+            .project_fingerprint
+            .is_some_and(|last| last != fingerprint)
+        {
+            self.dirty = true;
+        }
+        self.project_fingerprint = Some(fingerprint);
+    }
+
+    /// Marks the current project as saved, so it only counts as changed once it's edited again
+    fn mark_clean(&mut self) {
+        self.dirty = false;
+        self.project_fingerprint = None;
+    }
+
+    /// Runs an action from the project window, reporting how it went in the messages
+    fn run_project_action(&mut self, action: ProjectAction) {
+        let path = PathBuf::from(self.project_path.trim());
+        let result = match action {
+            ProjectAction::Export => self
+                .export_project(path.clone())
+                .map(|()| format!("Exported project to {}", path.display())),
+            ProjectAction::Import => self
+                .import_project(path.clone())
+                .map(|()| format!("Imported project from {}", path.display())),
+            ProjectAction::New => {
+                self.reset_project();
+                Ok("Started a new project".to_string())
+            }
+        };
+        if result.is_ok() {
+            self.mark_clean();
+        }
+
+        let message = match result {
+            Ok(message) => GuiMessage::PushMessage(message),
+            Err(err) => GuiMessage::PushError(err),
+        };
+        if let Some(gui_sender) = &self.gui_sender {
+            gui_sender.send(message).unwrap();
+        }
+    }
+
+    /// Writes the modules, ABIs, configs and block cache to a project file
+    pub fn export_project(&self, path: PathBuf) -> Result<(), String> {
+        let project = project::ProjectRef {
+            version: project::PROJECT_VERSION,
+            modules: &self.modules,
+            abis: &self.abis,
+            user_config: &self.user_config,
+            editor_config: &self.editor_config,
+            block_cache: &self.block_cache,
+        };
+        project::export(&path, &project)
+    }
+
+    /// Replaces the modules, ABIs, configs and block cache with the ones in a project file
+    pub fn import_project(&mut self, path: PathBuf) -> Result<(), String> {
+        let project = project::import(&path)?;
+
+        self.modules = project.modules;
+        self.abis = project.abis;
+        self.user_config = project.user_config;
+        self.editor_config = project.editor_config;
+        self.block_cache = project.block_cache;
+        self.user_config.fix_selection();
+
+        // Results are keyed by module id, which don't carry over between projects
+        self.module_results.clear();
+        self.source_errors.clear();
+
+        self.sync_threads();
+        Ok(())
+    }
+
+    /// Reads an ABI json file and adds it under the name, so handlers can use it
+    pub fn load_abi(&mut self, name: String, path: PathBuf) -> Result<(), String> {
+        let name = name.trim().to_string();
+        abis::load_abi(&mut self.abis, name.clone(), &path)?;
+
+        if let Some(worker_sender) = &self.worker_sender {
+            let message = WorkerMessage::LoadAbi(name.clone(), self.abis[&name].clone());
+            worker_sender.send(message).unwrap();
+        }
+        Ok(())
+    }
+
+    /// Renames a module, keeping the inputs of other modules pointing at it
+    pub fn rename_module(&mut self, old: &str, new: &str) {
+        modules::rename_module(&mut self.modules, old, new);
+    }
+
+    /// Checks the modules don't depend on each other in a loop, returning the cycle if they do
+    pub fn validate_module_graph(&self) -> Result<(), Vec<String>> {
+        modules::dependency_order(&self.modules).map(|_| ())
+    }
+
+    /// Builds the modules, unless there are none or they depend on each other in a cycle
+    pub fn build(&self) {
+        let (Some(worker_sender), Some(gui_sender)) = (&self.worker_sender, &self.gui_sender)
+        else {
+            return;
+        };
+
+        if self.modules.is_empty() {
+            let message = format!("Build failed: {}", panels::NO_MODULES);
+            gui_sender.send(GuiMessage::PushError(message)).unwrap();
+            return;
+        }
+
+        match self.validate_module_graph() {
+            Ok(()) => worker_sender.send(WorkerMessage::Build).unwrap(),
+            Err(cycle) => {
+                let message = format!(
+                    "Build failed: modules depend on each other in a cycle: {}",
+                    cycle.join(" -> ")
+                );
+                gui_sender.send(GuiMessage::PushError(message)).unwrap();
+            }
+        }
+    }
+
+    /// Returns `base`, or `base` with a numeric suffix if a module already has that name
+    pub fn unique_module_name(&self, base: &str) -> String {
+        modules::unique_name(&self.modules, base)
+    }
+
+    /// Applies a change the module panel or menu bar asked for.
+    /// Two modules with the same name would make inputs ambiguous, so added and renamed ones get a free name
+    fn apply_module_action(&mut self, action: ModuleAction) {
+        match action {
+            ModuleAction::Add(module) => {
+                let name = self.unique_module_name(module.name());
+                let module = module.clone_with_inputs(&name, module.inputs().clone());
+                self.modules.insert(random(), module);
+            }
+            ModuleAction::Rename(old, new) => {
+                let new = self.unique_module_name(&new);
+                self.rename_module(&old, &new);
+            }
+            ModuleAction::Build => self.build(),
+        }
+    }
+
+    /// Starts a new project with the default modules and config, and no messages or cached blocks
+    ///
+    /// The API key, ABIs and the background threads are kept, the threads just get the new config.
+    pub fn reset_project(&mut self) {
+        self.modules = Module::build_default_modules();
+        self.user_config = UserConfig::default();
+        self.editor_config = EditorConfig::default();
+        self.block_cache = BlockCache::default();
+        self.messages.clear();
+        self.dropped_messages = 0;
+        self.last_run_report = None;
+
+        // Results are keyed by module id, which don't carry over between projects
+        self.module_results.clear();
+        self.source_errors.clear();
+
+        // Drop the old project's functions from the repl
+        if let Some(worker_sender) = &self.worker_sender {
+            worker_sender.send(WorkerMessage::Reset).unwrap();
+        }
+        self.sync_threads();
+        self.mark_clean();
+    }
+
+    /// Generates the rhai source for every module, or the errors for the modules that couldn't be generated
+    pub fn source_file(&self) -> Result<String, Vec<String>> {
+        self.generate_source().map(|(source, _)| source)
+    }
+
+    /// Generates the rhai source along with where each module's part of it starts
+    pub fn generate_source(&self) -> Result<(String, SourceMap), Vec<String>> {
         let modules = &self.modules;
+
+        // Register inputs before the modules that use them. A cycle can't build anyway, so just keep it stable
+        let order = modules::dependency_order(modules).unwrap_or_else(|_| {
+            let mut ids = modules.keys().copied().collect::<Vec<_>>();
+            ids.sort_by_key(|id| (modules[id].name(), *id));
+            ids
+        });
+
         let mut source = String::new();
-        for module in modules.values() {
-            source.push_str(&module.register_module(&modules));
-            source.push_str(module.code());
-            source.push_str("\n");
+        let mut source_map = SourceMap::default();
+        let mut errors = Vec::new();
+        for module in order.iter().map(|id| &modules[id]) {
+            match module.register_module(&modules, &self.editor_config.source_type) {
+                Ok(register_code) => {
+                    source_map.push(source.matches('\n').count() + 1, module.name());
+                    source.push_str(&register_code);
+                    source.push_str(module.code());
+                    source.push_str("\n");
+                }
+                Err(err) => errors.push(err),
+            }
         }
 
-        source
+        if errors.is_empty() {
+            Ok((source, source_map))
+        } else {
+            Err(errors)
+        }
     }
 }
 
@@ -465,56 +2050,209 @@ impl eframe::App for EditorState {
     }
 
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-        let source_file = self.source_file();
+        let source_file = self.generate_source();
         let api_key = self.substreams_api_key.clone();
-        let endpoint = self.user_config.endpoint_list[self.user_config.selected_endpoint]
-            .url
-            .clone();
+        let endpoint = self.user_config.endpoint().url.clone();
 
         let Self {
             template_repo_path,
+            schema_path,
+            substreams_api_key,
+            api_key_saved,
             editor_config,
             view_config,
+            abis,
             messages,
+            dropped_messages,
             modules,
             display_welcome_message,
             worker_sender,
             gui_receiver,
             gui_sender,
             stream_sender,
+            stream_progress,
+            running_streams,
             message_search,
+            redaction,
+            last_run_report,
+            report_path,
+            source_path,
+            project_path,
+            log_path,
             block_cache,
+            module_results,
+            stored_outputs,
+            package_modules,
+            user_config,
+            compile_status,
+            checked_source,
+            source_changed_at,
+            source_errors,
+            panel_focus,
             ..
         } = self;
 
+        // Only when it changed, since setting the visuals restyles everything.
+        // The code views pick their light or dark theme from the visuals
+        if ctx.style().visuals.dark_mode != (view_config.theme == Theme::Dark) {
+            ctx.set_visuals(view_config.theme.visuals());
+        }
+
         let stream_sender = stream_sender.as_ref().unwrap();
         let worker_sender = worker_sender.as_ref().unwrap();
         let gui_receiver = gui_receiver.as_mut().unwrap();
         let gui_sender = gui_sender.as_ref().unwrap();
+        let channels = Channels {
+            gui: gui_sender,
+            worker: worker_sender,
+            stream: stream_sender,
+        };
 
         // In the gui thread, we listen for messages from the other threads
         while let Ok(msg) = gui_receiver.try_recv() {
+            let (source, msg) = match msg {
+                GuiMessage::Tagged(source, msg) => (Some(source), *msg),
+                msg => (None, msg),
+            };
+            let first_pushed = messages.len();
+
             match msg {
                 GuiMessage::PushMessage(msg) => {
                     let message = MessageKind::TextMessage(msg);
-                    messages.push(message);
+                    messages.push_back(message.into());
+                }
+                GuiMessage::PushError(msg) => {
+                    messages.push_back(MessageKind::ErrorMessage(msg).into());
+                }
+                GuiMessage::ClearMessages => {
+                    messages.clear();
+                    *dropped_messages = 0;
+                }
+                GuiMessage::PushBuild(build) => {
+                    messages.push_back(MessageKind::BuildMessage(build).into());
                 }
-                GuiMessage::ClearMessages => messages.clear(),
-                GuiMessage::PushJson(json_str) => {
-                    let value = serde_json::from_str(&json_str).unwrap();
+                GuiMessage::PushJson(value) => {
                     let message = MessageKind::JsonMessage(value);
-                    messages.push(message);
+                    messages.push_back(message.into());
+                }
+                GuiMessage::SetProgress(id, progress) => match progress {
+                    Some(progress) => {
+                        stream_progress.insert(id, progress);
+                    }
+                    None => {
+                        stream_progress.remove(&id);
+                    }
+                },
+                GuiMessage::SetRunning(id, label) => match label {
+                    Some(label) => {
+                        running_streams.insert(id, label);
+                    }
+                    None => {
+                        running_streams.remove(&id);
+                    }
+                },
+                GuiMessage::RunReport(report) => {
+                    messages.push_back(MessageKind::TextMessage(report.clone()).into());
+                    *last_run_report = Some(report);
+                }
+                GuiMessage::CheckResult(result) => {
+                    *compile_status = match result {
+                        Ok(()) => CompileStatus::Ok,
+                        Err(err) => CompileStatus::Error(err),
+                    };
+                }
+                GuiMessage::PushResult(module_name, _, value) => {
+                    // Looked up by name, since modules can share a handler
+                    let module_id = modules
+                        .iter()
+                        .find(|(_, module)| module.name() == module_name)
+                        .map(|(id, _)| *id);
+
+                    if let Some(id) = module_id {
+                        let results = module_results.entry(id).or_default();
+                        results.push_back(value.clone());
+                        while results.len() > user_config.results_per_module {
+                            results.pop_front();
+                        }
+                    }
+
+                    let message = MessageKind::JsonMessage(value);
+                    messages.push_back(message.into());
                 }
                 GuiMessage::SetBlock(cache_slot, json_str) => {
-                    let value: Value = serde_json::from_str(&json_str).unwrap();
-                    block_cache.set(cache_slot, value.clone());
+                    match set_block(block_cache, cache_slot, &json_str) {
+                        Ok(value) => {
+                            let message =
+                                MessageKind::TextMessage(format!("Slot {} set", cache_slot));
+                            messages.push_back(message.into());
+
+                            let message = MessageKind::JsonMessage(value);
+                            messages.push_back(message.into());
+                        }
+                        Err(message) => messages.push_back(message.into()),
+                    }
+                }
+                GuiMessage::SetLatestBlock(number) => {
+                    block_cache.set_fetch_block(number);
+                }
+                GuiMessage::SetPackageModules(package_file, names) => {
+                    package_modules.insert(package_file, names);
+                }
+                // Only stream tasks tag their messages, and they never tag them twice
+                GuiMessage::Tagged(..) => {}
+            }
+
+            for message in messages.range_mut(first_pushed..) {
+                message.source = source.clone();
+            }
+        }
 
-                    let message = MessageKind::TextMessage(format!("Block {} set", cache_slot));
-                    messages.push(message);
+        // egui's own Ctrl +/- shortcuts zoom too, so keep whatever the current zoom is for next time
+        view_config.zoom = ctx.zoom_factor();
 
-                    let message = MessageKind::JsonMessage(value);
-                    messages.push(message);
+        // Keep polling for progress while a stream runs, even if the user isn't interacting
+        if !stream_progress.is_empty() {
+            ctx.request_repaint_after(PROGRESS_INTERVAL);
+        }
+
+        focus::clear(ctx);
+
+        let (source_file, source_map) = match source_file {
+            Ok(generated) => {
+                source_errors.clear();
+                generated
+            }
+            Err(errors) => {
+                if *source_errors != errors {
+                    for error in errors.iter() {
+                        messages.push_back(MessageKind::ErrorMessage(error.clone()).into());
+                    }
+                    *source_errors = errors.clone();
                 }
+
+                // Make sure the source gets checked again once the errors are fixed
+                checked_source.clear();
+                *source_changed_at = None;
+                *compile_status = CompileStatus::Error(errors.join("\n"));
+                (String::new(), SourceMap::default())
+            }
+        };
+
+        cap_messages(messages, user_config.max_messages, dropped_messages);
+
+        // Check the source once it has stopped changing for a bit
+        if source_errors.is_empty() && *checked_source != source_file {
+            *checked_source = source_file.clone();
+            *source_changed_at = Some(Instant::now());
+        }
+        if let Some(changed_at) = *source_changed_at {
+            let elapsed = changed_at.elapsed();
+            if elapsed >= CHECK_DEBOUNCE {
+                *source_changed_at = None;
+                let message = WorkerMessage::Check(source_file.clone(), source_map.clone());
+                worker_sender.send(message).unwrap();
+            } else {
+                ctx.request_repaint_after(CHECK_DEBOUNCE - elapsed);
             }
         }
 
@@ -535,31 +2273,65 @@ impl eframe::App for EditorState {
                         ui.separator();
                         if ui.button("Close this message").clicked() {
                             *display_welcome_message = false;
+                            // It can be brought back from the Panels menu
+                            view_config.show_welcome_on_startup = false;
                         }
                     });
                 });
             return ();
         }
 
+        let mut module_actions = Vec::new();
         if view_config.show_modules {
-            egui::SidePanel::left("Modules")
+            module_actions = egui::SidePanel::left("Modules")
                 .max_width(250.0)
                 .show(ctx, |ui| {
-                    let channel = worker_sender.clone();
-                    let view = ModulePanel::new(ctx, channel, modules);
-                    ui.add(view)
-                });
+                    let view = ModulePanel::new(
+                        ctx,
+                        channels,
+                        modules,
+                        module_results,
+                        stored_outputs,
+                        block_cache,
+                        &mut view_config.module_sort,
+                    );
+                    view.show(ui)
+                })
+                .inner;
         }
 
         if view_config.show_block_cache {
+            let map_modules = map_modules(modules);
             Window::new("Block Cache").show(ctx, |ui| {
-                block_cache.show(ui, &api_key, &endpoint, stream_sender);
+                block_cache.show(
+                    ui,
+                    &api_key,
+                    &endpoint,
+                    &user_config.endpoint_list,
+                    channels,
+                    &map_modules,
+                );
             });
         }
 
         if view_config.show_messages {
             egui::SidePanel::right("Messages").show(ctx, |ui| {
-                panels::message_panel(ui, messages, message_search, gui_sender, worker_sender);
+                let context = panels::MessageContext {
+                    messages,
+                    dropped_messages: *dropped_messages,
+                    stream_progress,
+                    message_search,
+                    redaction,
+                    log_path,
+                };
+                panels::message_panel(
+                    ui,
+                    context,
+                    view_config,
+                    stored_outputs,
+                    editor_config,
+                    channels,
+                );
             });
         }
 
@@ -573,6 +2345,72 @@ impl eframe::App for EditorState {
 
                         ui.separator();
 
+                        ui.label("Source Block Type");
+                        ui.horizontal(|ui| {
+                            ui.text_edit_singleline(&mut editor_config.source_type);
+                            ComboBox::from_id_source("source_type")
+                                .selected_text("Common")
+                                .show_ui(ui, |ui| {
+                                    for source_type in modules::SOURCE_TYPES {
+                                        ui.selectable_value(
+                                            &mut editor_config.source_type,
+                                            source_type.to_string(),
+                                            *source_type,
+                                        );
+                                    }
+                                });
+                        });
+
+                        ui.separator();
+
+                        ui.horizontal(|ui| {
+                            ui.label("Output Modules");
+                            let package_file = &editor_config.substream_package;
+                            let known = package_modules.contains_key(package_file);
+                            let label = if known { "↻" } else { "Load from package" };
+                            if ui
+                                .small_button(label)
+                                .on_hover_text("Read the module names from the package")
+                                .clicked()
+                            {
+                                let message = StreamMessages::ListModules {
+                                    package_file: package_file.clone(),
+                                };
+                                stream_sender.send(message).unwrap();
+                            }
+                        });
+                        let known_modules = package_modules.get(&editor_config.substream_package);
+                        output_module_field(
+                            ui,
+                            "output_module",
+                            &mut editor_config.module_name,
+                            known_modules,
+                        );
+                        let mut removed = None;
+                        for (i, module_name) in
+                            editor_config.extra_output_modules.iter_mut().enumerate()
+                        {
+                            ui.horizontal(|ui| {
+                                output_module_field(
+                                    ui,
+                                    ("extra_output_module", i),
+                                    module_name,
+                                    known_modules,
+                                );
+                                if ui.small_button("-").clicked() {
+                                    removed = Some(i);
+                                }
+                            });
+                        }
+                        if let Some(i) = removed {
+                            editor_config.extra_output_modules.remove(i);
+                        }
+                        if ui.button("+ Add Module").clicked() {
+                            editor_config.extra_output_modules.push(String::new());
+                        }
+
+                        ui.separator();
+
                         ui.label("Start Block");
                         let mut start_block = editor_config.stream_start_block.to_string();
                         ui.text_edit_singleline(&mut start_block);
@@ -581,12 +2419,70 @@ impl eframe::App for EditorState {
                         }
                         ui.separator();
 
+                        // Substreams treats a stop block of 0 as streaming forever
+                        let mut live = editor_config.stream_stop_block == 0;
+                        if ui
+                            .checkbox(&mut live, "Stream live (no stop block)")
+                            .on_hover_text("Keep streaming new blocks until the stream is stopped")
+                            .changed()
+                        {
+                            editor_config.stream_stop_block = if live {
+                                0
+                            } else {
+                                editor_config.stream_start_block.max(0) as u64 + 10
+                            };
+                        }
+
                         ui.label("Stop Block");
                         let mut stop_block = editor_config.stream_stop_block.to_string();
-                        ui.text_edit_singleline(&mut stop_block);
+                        ui.add_enabled(!live, egui::TextEdit::singleline(&mut stop_block));
                         if let Ok(stop_block) = stop_block.parse::<u64>() {
                             editor_config.stream_stop_block = stop_block;
                         }
+
+                        ui.separator();
+
+                        ui.label("Webhook URL");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut editor_config.webhook.url)
+                                .hint_text("Leave empty to disable"),
+                        )
+                        .on_hover_text("Streamed outputs are POSTed here as json");
+                        if editor_config.webhook.is_enabled() {
+                            ui.add(
+                                egui::Slider::new(&mut editor_config.webhook.concurrency, 1..=32)
+                                    .text("Concurrent requests"),
+                            );
+                            ui.add(
+                                egui::Slider::new(&mut editor_config.webhook.retries, 0..=10)
+                                    .text("Retries"),
+                            );
+                        }
+
+                        if let Some(report) = last_run_report {
+                            ui.separator();
+
+                            ui.collapsing("Last Run Report", |ui| ui.label(report.as_str()));
+                            ui.horizontal(|ui| {
+                                ui.add(
+                                    egui::TextEdit::singleline(report_path)
+                                        .hint_text("run_report.txt"),
+                                );
+                                if ui.button("Save Report").clicked() {
+                                    let message = match fs::write(&*report_path, report.as_str()) {
+                                        Ok(()) => MessageKind::TextMessage(format!(
+                                            "Saved run report to {}",
+                                            report_path
+                                        )),
+                                        Err(err) => MessageKind::ErrorMessage(format!(
+                                            "Failed to save run report to {}: {}",
+                                            report_path, err
+                                        )),
+                                    };
+                                    messages.push_back(message.into());
+                                }
+                            });
+                        }
                     })
                 });
         }
@@ -595,43 +2491,641 @@ impl eframe::App for EditorState {
             panels::rust_view_ui(ctx, &source_file);
         }
 
+        if view_config.show_schema_import {
+            panels::schema_import(
+                ctx,
+                &mut view_config.show_schema_import,
+                schema_path,
+                modules,
+                gui_sender,
+            );
+        }
+
+        if view_config.show_save_source {
+            panels::save_source(
+                ctx,
+                &mut view_config.show_save_source,
+                source_path,
+                &source_file,
+                source_errors.is_empty(),
+                &editor_config.module_name,
+                gui_sender,
+            );
+        }
+
+        let mut project_action = None;
+        if view_config.show_project {
+            project_action = panels::project(ctx, &mut view_config.show_project, project_path);
+        }
+
+        let mut abi_to_load = None;
+        if view_config.show_abis {
+            abi_to_load = panels::abi_manager(ctx, &mut view_config.show_abis, abis);
+        }
+
+        if view_config.show_compile_errors {
+            if let CompileStatus::Error(err) = compile_status {
+                Window::new("Compile Errors")
+                    .open(&mut view_config.show_compile_errors)
+                    .show(ctx, |ui| {
+                        ui.colored_label(egui::Color32::RED, err.as_str());
+                    });
+            }
+        }
+
         if view_config.show_user_config {
+            let max_call_levels = user_config.max_call_levels;
+            let stream_timeout_secs = user_config.stream_timeout_secs;
+            let max_concurrent_streams = user_config.max_concurrent_streams;
             panels::user_config(
                 ctx,
-                &mut self.user_config,
+                user_config,
                 block_cache,
-                &endpoint,
-                &api_key,
-                stream_sender,
+                substreams_api_key,
+                api_key_saved,
+                channels,
+                &map_modules(modules),
             );
+
+            if user_config.max_call_levels != max_call_levels {
+                let message = WorkerMessage::SetMaxCallLevels(user_config.max_call_levels);
+                worker_sender.send(message).unwrap();
+            }
+
+            if user_config.stream_timeout_secs != stream_timeout_secs {
+                let duration = Duration::from_secs(user_config.stream_timeout_secs);
+                stream_sender
+                    .send(StreamMessages::SetTimeout(duration))
+                    .unwrap();
+            }
+
+            if user_config.max_concurrent_streams != max_concurrent_streams {
+                let limit = user_config.max_concurrent_streams;
+                stream_sender
+                    .send(StreamMessages::SetConcurrency(limit))
+                    .unwrap();
+            }
         }
 
-        egui::CentralPanel::default().show(ctx, |ui| {
+        let menu_action = egui::CentralPanel::default().show(ctx, |ui| {
+            let context = panels::MenuContext {
+                api_key: &api_key,
+                source_file: &source_file,
+                source_map: &source_map,
+                source_errors,
+                modules,
+                compile_status,
+                running_streams,
+            };
             panels::menu_bar(
                 ui,
+                context,
                 view_config,
                 editor_config,
-                template_repo_path,
-                &api_key,
-                &source_file,
-                worker_sender,
-                stream_sender,
+                display_welcome_message,
+                channels,
+                &mut module_actions,
+            )
+        });
+        let project_action = project_action.or(menu_action.inner);
+
+        // Panels register their focus targets while drawing, so cycle after everything is shown
+        if ctx.input(|i| i.key_pressed(Key::F6)) {
+            focus::cycle(ctx, panel_focus);
+        }
+
+        for action in module_actions {
+            self.apply_module_action(action);
+        }
+
+        if let Some((name, path)) = abi_to_load {
+            let message = match self.load_abi(name.clone(), path) {
+                Ok(()) => {
+                    self.dirty = true;
+                    GuiMessage::PushMessage(format!("Loaded ABI `{}`", name.trim()))
+                }
+                Err(err) => GuiMessage::PushError(err),
+            };
+            if let Some(gui_sender) = &self.gui_sender {
+                gui_sender.send(message).unwrap();
+            }
+        }
+
+        self.track_changes(ctx);
+
+        // Importing replaces most of the state, so it runs once nothing above is borrowing it
+        if let Some(action) = project_action {
+            if action.discards_changes() && self.dirty {
+                self.pending_project_action = Some(action);
+            } else {
+                self.run_project_action(action);
+            }
+        }
+
+        if let Some(action) = self.pending_project_action {
+            match panels::confirm_discard(ctx) {
+                Some(true) => {
+                    self.pending_project_action = None;
+                    self.run_project_action(action);
+                }
+                Some(false) => self.pending_project_action = None,
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use modules::tests::map;
+
+    #[test]
+    fn evaluating_the_source_again_replaces_it() {
+        let mut engine = Engine::new();
+        let mut scope = Scope::new();
+        scope.push("runs", 0 as rhai::INT);
+        let mut main_ast = AST::empty();
+        let mut ast_cache = AstCache::default();
+        let code = "fn map_a(x) { x } fn map_b(x) { x } runs += 1;";
+
+        for _ in 0..2 {
+            let result = eval_fresh(&mut engine, &mut scope, code, &mut main_ast, &mut ast_cache);
+            assert!(result.is_ok());
+        }
+
+        assert_eq!(main_ast.iter_functions().count(), 2);
+        // Stacked sources would run the first one's statements again
+        assert_eq!(scope.get_value::<rhai::INT>("runs"), Some(2));
+    }
+
+    #[test]
+    fn unchanged_source_reuses_its_compiled_ast() {
+        let mut engine = Engine::new();
+        let mut scope = Scope::new();
+        let mut main_ast = AST::empty();
+        let mut ast_cache = AstCache::default();
+        // As many handlers as a large project has
+        let code = (0..500)
+            .map(|i| format!("fn map_{i}(x) {{ let y = x + {i}; y * 2 }}\n"))
+            .collect::<String>();
+
+        let first = eval_fresh(
+            &mut engine,
+            &mut scope,
+            &code,
+            &mut main_ast,
+            &mut ast_cache,
+        );
+        assert!(first.is_ok());
+        assert_eq!(ast_cache.reused, None);
+
+        let second = eval_fresh(
+            &mut engine,
+            &mut scope,
+            &code,
+            &mut main_ast,
+            &mut ast_cache,
+        );
+        assert!(second.is_ok());
+        let (_, compile_time) = ast_cache.asts.values().next().unwrap();
+        assert_eq!(ast_cache.reused, Some(*compile_time));
+    }
+
+    #[test]
+    fn changed_scope_constants_recompile_the_source() {
+        let mut engine = Engine::new();
+        let mut scope = Scope::new();
+        let mut main_ast = AST::empty();
+        let mut ast_cache = AstCache::default();
+
+        for fee in [1, 3] {
+            scope.clear();
+            scope.push_constant("FEE", fee as rhai::INT);
+            let result = eval_fresh(
+                &mut engine,
+                &mut scope,
+                "FEE * 2",
+                &mut main_ast,
+                &mut ast_cache,
             );
+            assert_eq!(result.unwrap().as_int(), Ok(fee * 2));
+            assert_eq!(ast_cache.reused, None);
+        }
+    }
 
-            if ui.button("Eval Block for `foo`").clicked() {
-                let block = block_cache.get(1 as u8);
+    #[test]
+    fn loading_an_abi_sends_it_to_the_worker() {
+        let path = std::env::temp_dir().join("loading_an_abi_sends_it_to_the_worker.json");
+        let json = r#"[{"type": "event", "name": "Ping", "inputs": []}]"#;
+        fs::write(&path, json).unwrap();
 
-                gui_sender
-                    .send(GuiMessage::PushMessage(
-                        "Evaluating block for `foo`".to_string(),
-                    ))
-                    .unwrap();
+        let (worker_send, worker_rec) = mpsc::channel();
+        let mut state = EditorState {
+            worker_sender: Some(worker_send),
+            ..Default::default()
+        };
+        let result = state.load_abi(" ping ".to_string(), path.clone());
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(state.abis["ping"], json);
+        match worker_rec.try_recv() {
+            Ok(WorkerMessage::LoadAbi(name, abi)) => {
+                assert_eq!((name.as_str(), abi.as_str()), ("ping", json))
+            }
+            _ => panic!("the worker wasn't sent the ABI"),
+        }
+    }
 
-                let fn_name = "foo".to_string();
-                let args = vec![block.clone()];
-                let message = WorkerMessage::EvalWithArgs(fn_name, args);
-                worker_sender.send(message).unwrap();
+    #[test]
+    fn module_graph_names_both_modules_of_a_cycle() {
+        let mut state = EditorState::default();
+        state.modules.insert(1, map("a", &["b"]));
+        state.modules.insert(2, map("b", &["a"]));
+
+        let cycle = state.validate_module_graph().unwrap_err();
+        assert_eq!(cycle, ["a", "b", "a"]);
+    }
+
+    #[test]
+    fn adding_two_maps_gives_them_distinct_names() {
+        let mut state = EditorState::default();
+        for _ in 0..2 {
+            state.apply_module_action(ModuleAction::Add(map("map_a", &["BLOCK"])));
+        }
+
+        let mut names = state.modules.values().map(Module::name).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["map_a", "map_a_2"]);
+        let renamed = state
+            .modules
+            .values()
+            .find(|m| m.name() == "map_a_2")
+            .unwrap();
+        assert!(renamed.code().starts_with("fn map_a_2(BLOCK)"));
+    }
+
+    #[test]
+    fn source_registers_parents_before_children() {
+        let mut state = EditorState::default();
+        // The child sorts first by both id and name, so only the dependency order puts it last
+        state.modules.insert(1, map("sums", &["tallies"]));
+        state.modules.insert(2, map("tallies", &["BLOCK"]));
+
+        let source = state.source_file().unwrap();
+        let parent = source.find("fn tallies(").unwrap();
+        let child = source.find("fn sums(").unwrap();
+        assert!(parent < child, "{}", source);
+    }
+
+    #[test]
+    fn worker_exits_once_the_gui_receiver_is_dropped() {
+        let (worker_send, worker_rec) = mpsc::channel();
+        let (gui_send, gui_rec) = mpsc::channel();
+        let worker = spawn_worker(worker_rec, gui_send);
+
+        drop(gui_rec);
+        // Still connected, so only failing to reply can end the worker
+        worker_send
+            .send(WorkerMessage::Eval("1".to_string()))
+            .unwrap();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn worker_replies_in_the_order_it_was_sent() {
+        let (worker_send, worker_rec) = mpsc::channel();
+        let (gui_send, gui_rec) = mpsc::channel();
+        spawn_worker(worker_rec, gui_send);
+
+        for code in ["1", "2", "3"] {
+            worker_send
+                .send(WorkerMessage::Eval(code.to_string()))
+                .unwrap();
+        }
+
+        let replies = (0..3)
+            .map(
+                |_| match gui_rec.recv_timeout(Duration::from_secs(10)).unwrap() {
+                    GuiMessage::PushMessage(msg) => msg,
+                    _ => panic!("Expected an eval result"),
+                },
+            )
+            .collect::<Vec<_>>();
+        assert_eq!(replies, ["Result: 1", "Result: 2", "Result: 3"]);
+    }
+
+    #[test]
+    fn syntax_errors_report_their_line() {
+        let engine = Engine::new();
+        let err = engine.compile("let a = 1;\nlet b = ;").unwrap_err();
+
+        let message = compile_error_message(&err.0, err.1, None);
+        assert!(message.contains("at line 2"), "{}", message);
+    }
+
+    #[test]
+    fn capping_messages_keeps_the_newest() {
+        let mut messages = (0..1500)
+            .map(|i| Message::from(MessageKind::TextMessage(i.to_string())))
+            .collect::<VecDeque<_>>();
+        let mut dropped = 0;
+
+        cap_messages(&mut messages, default_max_messages(), &mut dropped);
+
+        assert_eq!(messages.len(), 1000);
+        assert_eq!(dropped, 500);
+        assert!(matches!(&messages[0].kind, MessageKind::TextMessage(msg) if msg == "500"));
+    }
+
+    #[test]
+    fn results_reach_the_gui_as_json_values() {
+        let result = Engine::new().eval::<Dynamic>(r#"#{ a: 1, b: [true, "x"] }"#);
+
+        match result_reply(result, "map_a".to_string(), "handle_a".to_string()) {
+            GuiMessage::PushResult(module_name, fn_name, value) => {
+                assert_eq!(module_name, "map_a");
+                assert_eq!(fn_name, "handle_a");
+                assert_eq!(value, serde_json::json!({ "a": 1, "b": [true, "x"] }));
             }
-        });
+            _ => panic!("Expected a result"),
+        }
+    }
+
+    #[test]
+    fn dependency_results_are_kept_under_their_module() {
+        let engine = Engine::new();
+        let mut scope = Scope::new();
+        let ast = engine.compile("fn passthrough(x) { x }").unwrap();
+        let step = |name: &str, input: &str| DepStep {
+            name: name.to_string(),
+            handler: "passthrough".to_string(),
+            inputs: vec![input.to_string()],
+            params: String::new(),
+            store: false,
+        };
+        let steps = vec![step("map_a", "BLOCK"), step("map_b", "map_a")];
+        let (gui_send, gui_rec) = mpsc::channel();
+
+        let last = eval_with_deps(
+            &engine,
+            &mut scope,
+            &ast,
+            &HashMap::new(),
+            steps,
+            serde_json::json!({ "number": 1 }),
+            &gui_send,
+        );
+
+        let module_name = |reply| match reply {
+            Some(GuiMessage::PushResult(module_name, _, _)) => module_name,
+            _ => panic!("Expected a result"),
+        };
+        assert_eq!(module_name(gui_rec.try_recv().ok()), "map_a");
+        assert_eq!(module_name(last), "map_b");
+    }
+
+    #[test]
+    fn a_store_step_outputs_what_it_wrote() {
+        let mut engine = Engine::new();
+        local_store::register(&mut engine);
+        let mut scope = Scope::new();
+        let ast = engine
+            .compile(
+                r#"fn block_number(BLOCK) { BLOCK.number }
+                fn totals(block_number, s) { s.set("last", block_number); s.add("seen", 1); 0 }"#,
+            )
+            .unwrap();
+        let step = |name: &str, input: &str, store| DepStep {
+            name: name.to_string(),
+            handler: name.to_string(),
+            inputs: vec![input.to_string()],
+            params: String::new(),
+            store,
+        };
+        let steps = vec![
+            step("block_number", "BLOCK", false),
+            step("totals", "block_number", true),
+        ];
+        let (gui_send, _gui_rec) = mpsc::channel();
+
+        let last = eval_with_deps(
+            &engine,
+            &mut scope,
+            &ast,
+            &HashMap::new(),
+            steps,
+            serde_json::json!({ "number": 12 }),
+            &gui_send,
+        );
+
+        match last {
+            Some(GuiMessage::PushResult(module_name, _, value)) => {
+                assert_eq!(module_name, "totals");
+                assert_eq!(value, serde_json::json!({ "last": 12, "seen": 1 }));
+            }
+            _ => panic!("Expected the store's result"),
+        }
+    }
+
+    #[test]
+    fn a_store_handler_is_passed_a_local_store() {
+        let mut engine = Engine::new();
+        local_store::register(&mut engine);
+        let mut scope = Scope::new();
+        let ast = engine
+            .compile("fn template_sfn(test_map, s) { s.set(test_map.number); }")
+            .unwrap();
+        let block = serde_json::from_value(serde_json::json!({ "number": 7 })).unwrap();
+
+        let output =
+            call_handler(&engine, &mut scope, &ast, "template_sfn", vec![block], true).unwrap();
+        let output = output.cast::<rhai::Map>();
+        assert_eq!(output[local_store::VALUE_KEY].as_int(), Ok(7));
+    }
+
+    #[test]
+    fn an_invalid_block_leaves_the_slot_as_it_was() {
+        let mut block_cache = BlockCache::default();
+        let block = serde_json::json!({ "number": 1 });
+        block_cache.set(0, block.clone());
+
+        let result = set_block(&mut block_cache, 0, r#"{"number": 2"#);
+        match result {
+            Err(MessageKind::ErrorMessage(message)) => {
+                assert!(message.starts_with("The block for slot 0 isn't valid json: "));
+                assert!(message.ends_with(r#"{"number": 2"#));
+            }
+            _ => panic!("Expected an error message"),
+        }
+        assert_eq!(block_cache.get(0), &block);
+    }
+
+    #[test]
+    fn a_valid_block_is_set_in_its_slot() {
+        let mut block_cache = BlockCache::default();
+        let block = set_block(&mut block_cache, 5, r#"{"number": 2}"#).ok();
+        assert_eq!(block_cache.get(5), &serde_json::json!({ "number": 2 }));
+        assert_eq!(block.as_ref(), Some(block_cache.get(5)));
+    }
+
+    #[test]
+    fn invalid_json_is_reported_with_a_snippet() {
+        // Multi byte characters, so cutting the snippet by bytes would panic
+        let data = format!("{{\"name\": \"{}", "é".repeat(INVALID_JSON_SNIPPET * 2));
+        let err = serde_json::from_str::<Value>(&data).unwrap_err();
+
+        let message = invalid_json_message("The stream sent invalid json", &err, &data);
+        assert!(message.starts_with("The stream sent invalid json: "));
+        assert!(message.ends_with('…'));
+    }
+
+    /// Takes as many permits as the limit allows right now
+    fn take_permits(limit: &StreamLimit) -> Vec<OwnedSemaphorePermit> {
+        std::iter::from_fn(|| limit.semaphore.clone().try_acquire_owned().ok()).collect()
+    }
+
+    #[test]
+    fn lowering_the_stream_limit_holds_once_running_tasks_finish() {
+        let mut limit = StreamLimit::new(4);
+        let running = take_permits(&limit);
+        assert_eq!(running.len(), 4);
+
+        limit.set_max(2);
+        // Two finish, their permits are owed so nothing new starts
+        let mut running = running.into_iter();
+        for permit in running.by_ref().take(2) {
+            limit.release(permit);
+        }
+        assert!(take_permits(&limit).is_empty());
+
+        for permit in running {
+            limit.release(permit);
+        }
+        assert_eq!(take_permits(&limit).len(), 2);
+    }
+
+    #[test]
+    fn raising_the_stream_limit_pays_off_owed_permits_first() {
+        let mut limit = StreamLimit::new(4);
+        let running = take_permits(&limit);
+        limit.set_max(1);
+        limit.set_max(3);
+
+        for permit in running {
+            limit.release(permit);
+        }
+        assert_eq!(take_permits(&limit).len(), 3);
+    }
+
+    #[test]
+    fn lowering_an_idle_stream_limit_forgets_its_free_permits() {
+        let mut limit = StreamLimit::new(4);
+        limit.set_max(2);
+        assert_eq!(take_permits(&limit).len(), 2);
+    }
+
+    #[test]
+    fn reset_project_starts_over_but_keeps_the_api_key() {
+        let (gui_send, _gui_rec) = mpsc::channel();
+        let (worker_send, worker_rec) = mpsc::channel();
+        let (stream_send, _stream_rec) = unbounded_channel();
+        let mut state = EditorState {
+            substreams_api_key: "server_abc123".to_string(),
+            dirty: true,
+            gui_sender: Some(gui_send),
+            worker_sender: Some(worker_send),
+            stream_sender: Some(stream_send),
+            ..EditorState::default()
+        };
+        state.abis.insert("erc20".into(), abis::ERC20.to_string());
+        state.block_cache.set(0, serde_json::json!({ "number": 1 }));
+        state.modules.insert(1, map("map_a", &["BLOCK"]));
+        state.editor_config.stream_start_block = 100;
+        state
+            .messages
+            .push_back(MessageKind::TextMessage("hello".to_string()).into());
+
+        state.reset_project();
+
+        let mut names = state.modules.values().map(Module::name).collect::<Vec<_>>();
+        let mut default_names = Module::build_default_modules()
+            .into_values()
+            .map(|module| module.name().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        default_names.sort();
+        assert_eq!(names, default_names);
+        assert_eq!(
+            state.editor_config.stream_start_block,
+            EditorConfig::default().stream_start_block
+        );
+        assert!(state.messages.is_empty());
+        assert!(state.block_cache.get(0).is_null());
+        assert!(!state.dirty);
+
+        assert_eq!(state.substreams_api_key, "server_abc123");
+        assert!(state.abis.contains_key("erc20"));
+        assert!(state.gui_sender.is_some());
+        assert!(state.worker_sender.is_some());
+        assert!(state.stream_sender.is_some());
+        // The worker is told to drop the old project's functions over the channel it still has
+        assert!(matches!(worker_rec.try_recv(), Ok(WorkerMessage::Reset)));
+    }
+
+    #[test]
+    fn runaway_recursion_reports_the_call_depth() {
+        let mut engine = Engine::new();
+        engine.set_max_call_levels(16);
+        let err = engine
+            .eval::<Dynamic>("fn down(n) { down(n + 1) } down(0)")
+            .unwrap_err();
+
+        assert!(is_stack_overflow(&err), "{:?}", err);
+        assert!(error_message(&err, None).contains("max call depth"));
+    }
+
+    fn stream_config(start: i64, stop: u64) -> EditorConfig {
+        EditorConfig {
+            stream_start_block: start,
+            stream_stop_block: stop,
+            ..EditorConfig::default()
+        }
+    }
+
+    #[test]
+    fn stop_block_must_be_after_the_start_block() {
+        assert!(stream_config(100, 200).validate().is_ok());
+        assert!(stream_config(100, 100).validate().is_err());
+        assert!(stream_config(200, 100).validate().is_err());
+    }
+
+    #[test]
+    fn zero_stop_block_streams_live() {
+        assert!(stream_config(100, 0).validate().is_ok());
+        assert!(stream_config(-10, 0).validate().is_ok());
+        // Relative to the chain head, so an absolute stop can't be checked against it
+        assert!(stream_config(-10, 100).validate().is_err());
+    }
+
+    #[test]
+    fn api_key_must_be_set() {
+        assert!(validate_api_key("server_abc123").is_ok());
+        assert!(validate_api_key("").is_err());
+        assert!(validate_api_key("  ").is_err());
+    }
+
+    #[test]
+    fn endpoint_urls_need_a_port() {
+        assert!(Endpoint::validate_url("https://mainnet.eth.streamingfast.io:443").is_ok());
+        assert!(Endpoint::validate_url("https://mainnet.eth.streamingfast.io").is_err());
+    }
+
+    #[test]
+    fn endpoint_urls_need_an_https_or_grpc_scheme() {
+        assert!(Endpoint::validate_url("grpc://localhost:9000").is_ok());
+        let err = Endpoint::validate_url("http://mainnet.eth.streamingfast.io:443").unwrap_err();
+        assert!(err.contains("scheme"), "{}", err);
     }
 }
@@ -0,0 +1,146 @@
+//! A stand-in for a substreams store, so store handlers can be run locally instead of only in a stream
+//!
+//! Handlers write with `s.set(key, value)`, `s.set(value)` and `s.add(key, delta)`, and read back with `s.get(key)`.
+//! Whatever was written ends up in a map, which is taken as the store's output.
+
+use std::sync::{Arc, Mutex};
+
+use rhai::{Dynamic, Engine, EvalAltResult, Map, FLOAT, INT};
+
+/// The key `s.set(value)` writes under, for stores that keep a single value
+pub const VALUE_KEY: &str = "value";
+
+/// Cloning shares the values, so the store handed to a handler can still be read once it returns
+#[derive(Clone, Default)]
+pub struct LocalStore(Arc<Mutex<Map>>);
+
+impl LocalStore {
+    /// Everything written to the store so far
+    pub fn to_map(&self) -> Map {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Registers the store type and its methods
+pub fn register(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<LocalStore>("Store")
+        .register_fn(
+            "set",
+            |store: &mut LocalStore, key: &str, value: Dynamic| {
+                store.0.lock().unwrap().insert(key.into(), value);
+            },
+        )
+        .register_fn("set", |store: &mut LocalStore, value: Dynamic| {
+            store.0.lock().unwrap().insert(VALUE_KEY.into(), value);
+        })
+        .register_fn("add", add)
+        .register_fn("get", |store: &mut LocalStore, key: &str| {
+            store
+                .0
+                .lock()
+                .unwrap()
+                .get(key)
+                .cloned()
+                .unwrap_or(Dynamic::UNIT)
+        });
+}
+
+/// Adds the delta to the number under the key, starting from 0 if the key hasn't been written
+fn add(store: &mut LocalStore, key: &str, delta: Dynamic) -> Result<(), Box<EvalAltResult>> {
+    let mut values = store.0.lock().unwrap();
+    let current = values.get(key).cloned().unwrap_or(Dynamic::from_int(0));
+
+    let sum = match (current.as_int(), delta.as_int()) {
+        (Ok(current), Ok(delta)) => current
+            .checked_add(delta)
+            .map(Dynamic::from_int)
+            .ok_or_else(|| format!("Adding {} to `{}` overflows", delta, key))?,
+        _ => match (as_float(&current), as_float(&delta)) {
+            (Some(current), Some(delta)) => Dynamic::from_float(current + delta),
+            _ => {
+                return Err(format!(
+                    "Can't add a {} to `{}`, which is a {}",
+                    delta.type_name(),
+                    key,
+                    current.type_name()
+                )
+                .into())
+            }
+        },
+    };
+
+    values.insert(key.into(), sum);
+    Ok(())
+}
+
+fn as_float(value: &Dynamic) -> Option<FLOAT> {
+    value
+        .as_float()
+        .ok()
+        .or_else(|| value.as_int().ok().map(|int: INT| int as FLOAT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rhai::Scope;
+
+    /// Runs the script with a store in scope as `s`, returning what it wrote
+    fn run(script: &str) -> Result<Map, Box<EvalAltResult>> {
+        let mut engine = Engine::new();
+        register(&mut engine);
+        let store = LocalStore::default();
+        let mut scope = Scope::new();
+        scope.push("s", store.clone());
+
+        engine.run_with_scope(&mut scope, script)?;
+        Ok(store.to_map())
+    }
+
+    #[test]
+    fn set_writes_under_the_key_or_the_value_key() {
+        let written = run(r#"s.set("a", 1); s.set(2); s.set("b", s.get("a") + 10);"#).unwrap();
+        assert_eq!(written["a"].as_int(), Ok(1));
+        assert_eq!(written[VALUE_KEY].as_int(), Ok(2));
+        assert_eq!(written["b"].as_int(), Ok(11));
+    }
+
+    #[test]
+    fn get_of_an_unwritten_key_is_unit() {
+        let written = run(r#"s.set("missing", s.get("missing") == ());"#).unwrap();
+        assert_eq!(written["missing"].as_bool(), Ok(true));
+    }
+
+    #[test]
+    fn add_sums_ints_from_zero() {
+        let written = run(r#"s.add("n", 2); s.add("n", 3);"#).unwrap();
+        assert_eq!(written["n"].as_int(), Ok(5));
+    }
+
+    #[test]
+    fn add_mixing_ints_and_floats_gives_a_float() {
+        let written = run(r#"s.add("n", 2); s.add("n", 0.5);"#).unwrap();
+        assert_eq!(written["n"].as_float(), Ok(2.5));
+    }
+
+    #[test]
+    fn add_reports_overflow() {
+        let err = run(r#"s.add("n", 9223372036854775807); s.add("n", 1);"#).unwrap_err();
+        assert!(
+            err.to_string().contains("Adding 1 to `n` overflows"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn add_rejects_what_isnt_a_number() {
+        let err = run(r#"s.add("n", "one");"#).unwrap_err();
+        assert!(
+            err.to_string().contains("Can't add a string to `n`"),
+            "{}",
+            err
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use eframe::{run_native, HardwareAcceleration, NativeOptions};
-use rhai_egui::EditorState;
+use rhai_egui::{EditorState, APP_ID};
 
 use dotenv::dotenv;
 use std::env;
@@ -13,7 +13,7 @@ fn main() {
     //native_options.hardware_acceleration = HardwareAcceleration::Off;
 
     run_native(
-        "Substreams Editor",
+        APP_ID,
         native_options,
         Box::new(|cc| Box::new(EditorState::new(cc, api_key))),
     )
@@ -1,14 +1,193 @@
 use rand::{random, RngCore};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-#[derive(Serialize, Deserialize)]
+/// The current time in seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Rhai keywords, keyword functions and reserved words, which can't be used as function names
+const RHAI_KEYWORDS: &[&str] = &[
+    "true",
+    "false",
+    "let",
+    "const",
+    "if",
+    "else",
+    "switch",
+    "do",
+    "while",
+    "until",
+    "loop",
+    "for",
+    "in",
+    "continue",
+    "break",
+    "return",
+    "throw",
+    "try",
+    "catch",
+    "fn",
+    "private",
+    "import",
+    "export",
+    "as",
+    "global",
+    "this",
+    "Fn",
+    "call",
+    "curry",
+    "type_of",
+    "print",
+    "debug",
+    "eval",
+    "is_def_fn",
+    "is_def_var",
+    "is_shared",
+    "var",
+    "static",
+    "shared",
+    "goto",
+    "exit",
+    "match",
+    "case",
+    "public",
+    "protected",
+    "new",
+    "use",
+    "with",
+    "module",
+    "package",
+    "super",
+    "thread",
+    "spawn",
+    "go",
+    "await",
+    "async",
+    "sync",
+    "yield",
+    "default",
+    "void",
+    "null",
+    "nil",
+];
+
+/// The block type `BLOCK` inputs are, unless another chain is picked
+pub const DEFAULT_SOURCE_TYPE: &str = "sf.ethereum.type.v2.Block";
+
+/// Block types of the chains substreams commonly runs on, offered for the `BLOCK` input
+pub const SOURCE_TYPES: &[&str] = &[
+    "sf.ethereum.type.v2.Block",
+    "sf.near.type.v1.Block",
+    "sf.solana.type.v1.Block",
+    "sf.cosmos.type.v2.Block",
+    "sf.arweave.type.v1.Block",
+    "sf.bitcoin.type.v1.Block",
+    "sf.substreams.v1.Clock",
+];
+
+/// How modules are ordered in the module panel
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq)]
+pub enum ModuleSort {
+    #[default]
+    Name,
+    LastModified,
+    Kind,
+}
+
+impl ModuleSort {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ModuleSort::Name => "Name",
+            ModuleSort::LastModified => "Last Modified",
+            ModuleSort::Kind => "Kind",
+        }
+    }
+}
+
+/// Which module each part of the generated source came from, so errors in it can name their module
+#[derive(Clone, Default, PartialEq)]
+pub struct SourceMap {
+    /// The line each module's part starts on, counting from 1, in source order
+    starts: Vec<(usize, String)>,
+}
+
+impl SourceMap {
+    /// Records that the module's part starts on the line
+    pub fn push(&mut self, line: usize, module: &str) {
+        self.starts.push((line, module.to_string()));
+    }
+
+    /// The module whose part of the source contains the line
+    pub fn module_at(&self, line: usize) -> Option<&str> {
+        self.starts
+            .iter()
+            .take_while(|(start, _)| *start <= line)
+            .last()
+            .map(|(_, module)| module.as_str())
+    }
+}
+
+/// Starter modules for common substreams patterns
+#[derive(Clone, Copy, PartialEq)]
+pub enum TemplateKind {
+    BlockFilter,
+    EventDecoder,
+    CounterStore,
+    GraphOut,
+}
+
+impl TemplateKind {
+    pub const ALL: [TemplateKind; 4] = [
+        TemplateKind::BlockFilter,
+        TemplateKind::EventDecoder,
+        TemplateKind::CounterStore,
+        TemplateKind::GraphOut,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TemplateKind::BlockFilter => "Block filter map",
+            TemplateKind::EventDecoder => "Event decoder map",
+            TemplateKind::CounterStore => "Counter store (add)",
+            TemplateKind::GraphOut => "graph_out map",
+        }
+    }
+
+    /// The module name the template starts with, made unique when it's added
+    pub fn base_name(&self) -> &'static str {
+        match self {
+            TemplateKind::BlockFilter => "filter_transactions",
+            TemplateKind::EventDecoder => "decode_events",
+            TemplateKind::CounterStore => "count_transactions",
+            TemplateKind::GraphOut => "graph_out",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum Module {
     Map {
         name: String,
         code: String,
         inputs: Vec<String>,
         editing: bool,
+        /// Unix timestamp of the last edit, 0 for modules saved before this was tracked
+        #[serde(default)]
+        last_modified: u64,
+        /// The rhai function the module runs, when it isn't the one named after the module
+        #[serde(default)]
+        handler: Option<String>,
+        /// The default value of a `PARAMS` input
+        #[serde(default)]
+        params: String,
     },
     Store {
         name: String,
@@ -16,9 +195,22 @@ pub enum Module {
         inputs: Vec<String>,
         update_policy: String,
         editing: bool,
+        #[serde(default)]
+        last_modified: u64,
+        /// The name of the handler's last parameter, which the store is passed as
+        #[serde(default = "default_store_param")]
+        store_param: String,
+        #[serde(default)]
+        handler: Option<String>,
+        #[serde(default)]
+        params: String,
     },
 }
 
+pub fn default_store_param() -> String {
+    "s".to_string()
+}
+
 impl Module {
     pub fn name(&self) -> &str {
         match self {
@@ -27,6 +219,65 @@ impl Module {
         }
     }
 
+    pub fn name_mut(&mut self) -> &mut String {
+        match self {
+            Module::Map { name, .. } => name,
+            Module::Store { name, .. } => name,
+        }
+    }
+
+    /// The rhai function the module runs, named after the module unless another was set
+    pub fn handler(&self) -> &str {
+        let (name, handler) = match self {
+            Module::Map { name, handler, .. } => (name, handler),
+            Module::Store { name, handler, .. } => (name, handler),
+        };
+        handler.as_deref().unwrap_or(name)
+    }
+
+    pub fn handler_mut(&mut self) -> &mut Option<String> {
+        match self {
+            Module::Map { handler, .. } => handler,
+            Module::Store { handler, .. } => handler,
+        }
+    }
+
+    pub fn params(&self) -> &str {
+        match self {
+            Module::Map { params, .. } => params,
+            Module::Store { params, .. } => params,
+        }
+    }
+
+    pub fn params_mut(&mut self) -> &mut String {
+        match self {
+            Module::Map { params, .. } => params,
+            Module::Store { params, .. } => params,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Module::Map { .. } => "map",
+            Module::Store { .. } => "store",
+        }
+    }
+
+    pub fn last_modified(&self) -> u64 {
+        match self {
+            Module::Map { last_modified, .. } => *last_modified,
+            Module::Store { last_modified, .. } => *last_modified,
+        }
+    }
+
+    /// Marks the module as modified now
+    pub fn touch(&mut self) {
+        match self {
+            Module::Map { last_modified, .. } => *last_modified = now(),
+            Module::Store { last_modified, .. } => *last_modified = now(),
+        }
+    }
+
     pub fn code(&self) -> &str {
         match self {
             Module::Map { code, .. } => code,
@@ -69,7 +320,201 @@ impl Module {
         }
     }
 
-    fn generate_input_code(input: &str, module_map: &HashMap<i64, Module>) -> String {
+    /// Checks the name is a valid rhai function name, since it's used as the handler name
+    pub fn validate_name(name: &str) -> Result<(), String> {
+        let mut chars = name.chars();
+        match chars.next() {
+            None => return Err("The name can't be empty".to_string()),
+            Some(c) if !(c.is_ascii_alphabetic() || c == '_') => {
+                return Err("The name must start with a letter or `_`".to_string())
+            }
+            _ => {}
+        }
+
+        if !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err("The name can only contain letters, digits and `_`".to_string());
+        }
+
+        if name == "BLOCK" {
+            return Err("`BLOCK` is reserved for the block input".to_string());
+        }
+
+        if name == "PARAMS" {
+            return Err("`PARAMS` is reserved for the params input".to_string());
+        }
+
+        if RHAI_KEYWORDS.contains(&name) {
+            return Err(format!("`{}` is a rhai keyword", name));
+        }
+
+        Ok(())
+    }
+
+    /// The update policies substreams supports for stores
+    pub fn valid_update_policies() -> &'static [&'static str] {
+        &["set", "setOnce", "add", "min", "max", "append"]
+    }
+
+    /// Turns the module into a store, keeping its name, code and inputs
+    pub fn convert_to_store(self, update_policy: String) -> Module {
+        match self {
+            Module::Map {
+                name,
+                code,
+                inputs,
+                handler,
+                params,
+                ..
+            } => Module::Store {
+                name,
+                code,
+                inputs,
+                update_policy,
+                editing: true,
+                last_modified: now(),
+                store_param: default_store_param(),
+                handler,
+                params,
+            },
+            store @ Module::Store { .. } => store,
+        }
+    }
+
+    /// Turns the module into a map, keeping its name, code and inputs
+    pub fn convert_to_map(self) -> Module {
+        match self {
+            Module::Store {
+                name,
+                code,
+                inputs,
+                handler,
+                params,
+                ..
+            } => Module::Map {
+                name,
+                code,
+                inputs,
+                editing: true,
+                last_modified: now(),
+                handler,
+                params,
+            },
+            map @ Module::Map { .. } => map,
+        }
+    }
+
+    /// Copies the module under a new name with new inputs, renaming the handler to match.
+    /// A handler that was set separately is kept, so the copy calls the same function
+    pub fn clone_with_inputs(&self, name: &str, inputs: Vec<String>) -> Module {
+        let mut module = self.clone();
+        if self.handler() == self.name() {
+            let handler = format!("fn {}(", self.name());
+            let code = module
+                .code()
+                .replacen(&handler, &format!("fn {}(", name), 1);
+            *module.code_mut() = code;
+        }
+
+        *module.name_mut() = name.to_string();
+        *module.inputs_mut() = inputs;
+        *module.editing_mut() = true;
+        module.touch();
+        module
+    }
+
+    /// The handler signature the inputs call for, eg `fn name(BLOCK, foo, s)`.
+    /// Stores take the store to write to after their inputs, named by `store_param`
+    pub fn expected_signature(&self) -> String {
+        let mut params = self.inputs().clone();
+        if let Module::Store { store_param, .. } = self {
+            params.push(store_param.clone());
+        }
+        format!("fn {}({})", self.handler(), params.join(", "))
+    }
+
+    /// Where the handler's signature is in the code, from `fn` up to the closing paren
+    fn signature_range(&self) -> Option<std::ops::Range<usize>> {
+        let code = self.code();
+        let start = code.find(&format!("fn {}(", self.handler()))?;
+        let end = start + code[start..].find(')')? + 1;
+        Some(start..end)
+    }
+
+    /// The handler's signature as written in the code, if the handler can be found
+    pub fn signature(&self) -> Option<&str> {
+        self.signature_range().map(|range| &self.code()[range])
+    }
+
+    /// Whether the handler's parameters match the inputs, ignoring whitespace.
+    /// A handler that can't be found counts as out of sync
+    pub fn signature_in_sync(&self) -> bool {
+        let strip = |s: &str| s.split_whitespace().collect::<String>();
+        self.signature()
+            .is_some_and(|signature| strip(signature) == strip(&self.expected_signature()))
+    }
+
+    /// Rewrites the handler's signature to match the inputs, returning false if the handler couldn't be found
+    pub fn sync_signature(&mut self) -> bool {
+        let Some(range) = self.signature_range() else {
+            return false;
+        };
+        let expected = self.expected_signature();
+        self.code_mut().replace_range(range, &expected);
+        true
+    }
+
+    /// Common mistakes in the code, found with simple text checks rather than parsing it
+    ///
+    /// Every module's code should define its handler, and a store's handler should use
+    /// the store it's given, since otherwise nothing is ever written to it.
+    pub fn lint(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let code = self.code();
+
+        if self.signature().is_none() {
+            let other = code
+                .split("fn ")
+                .skip(1)
+                .filter_map(|rest| rest.split_once('('))
+                .map(|(name, _)| name.trim())
+                .find(|name| !name.is_empty());
+            if let Some(other) = other {
+                let expected = if self.handler() == self.name() {
+                    "like the module"
+                } else {
+                    "as set in the module configuration"
+                };
+                warnings.push(format!(
+                    "The handler is named `{}`, it must be named `{}` {}",
+                    other,
+                    self.handler(),
+                    expected
+                ));
+            }
+        }
+
+        if let Module::Store { store_param, .. } = self {
+            let body = match self.signature_range() {
+                Some(range) => &code[range.end..],
+                None => code,
+            };
+            if !contains_word(body, store_param) {
+                warnings.push(format!(
+                    "The store parameter `{}` is never used, so nothing is written to the store",
+                    store_param
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    fn generate_input_code(
+        input: &str,
+        module_map: &HashMap<i64, Module>,
+        source_type: &str,
+        params: &str,
+    ) -> Result<String, String> {
         let module = module_map.iter().find(|(_, module)| module.name() == input);
         match module {
             Some((
@@ -79,10 +524,9 @@ impl Module {
                     code,
                     inputs,
                     editing,
+                    ..
                 },
-            )) => {
-                format!("#{{kind: \"map\", name: \"{name}\"}}")
-            }
+            )) => Ok(format!("#{{kind: \"map\", name: \"{name}\"}}")),
             Some((
                 _num,
                 Module::Store {
@@ -91,45 +535,135 @@ impl Module {
                     inputs,
                     update_policy,
                     editing,
+                    ..
                 },
-            )) => {
-                format!("#{{kind: \"store\", name: \"{name}\"}}")
-            }
+            )) => Ok(format!("#{{kind: \"store\", name: \"{name}\"}}")),
             None => {
                 if input == "BLOCK" {
-                    "#{kind: \"source\"}".to_string()
+                    Ok(format!("#{{kind: \"source\", type: \"{source_type}\"}}"))
+                } else if input == "PARAMS" {
+                    let value = rhai_string(params);
+                    Ok(format!("#{{kind: \"params\", value: {value}}}"))
                 } else {
-                    panic!("Unknown input: {}", input)
+                    Err(format!("Unknown input: {}", input))
                 }
             }
         }
     }
 
-    pub fn register_module(&self, module_map: &HashMap<i64, Module>) -> String {
+    /// The code registering the module, with `BLOCK` inputs taken as blocks of `source_type`
+    /// and `PARAMS` inputs defaulting to the module's params
+    pub fn register_module(
+        &self,
+        module_map: &HashMap<i64, Module>,
+        source_type: &str,
+    ) -> Result<String, String> {
         let name = self.name();
-        let register_function = match self {
-            Module::Map { .. } => "add_mfn",
-            Module::Store { .. } => "add_sfn",
+        let handler = self.handler();
+        let (register_function, store_metadata) = match self {
+            Module::Map { .. } => ("add_mfn", String::new()),
+            Module::Store { update_policy, .. } => (
+                "add_sfn",
+                format!("\n    updatePolicy: \"{update_policy}\","),
+            ),
         };
 
         let input_code = self
             .inputs()
             .iter()
-            .map(|input| Self::generate_input_code(input, module_map))
-            .collect::<Vec<String>>()
+            .map(|input| Self::generate_input_code(input, module_map, source_type, self.params()))
+            .collect::<Result<Vec<String>, String>>()
+            .map_err(|err| format!("Module `{}`: {}", name, err))?
             .join(",");
 
         let code = format!(
             r#"
 {register_function}(#{{
     name: "{name}",
-    inputs: [{input_code}],
-    handler: "{name}"
+    inputs: [{input_code}],{store_metadata}
+    handler: "{handler}"
 }});
 "#
         );
 
-        code
+        Ok(code)
+    }
+
+    /// A new module from a template, named by `TemplateKind::base_name`
+    pub fn template(kind: TemplateKind) -> Module {
+        let name = kind.base_name();
+        let code = match kind {
+            TemplateKind::BlockFilter => format!(
+                r#"fn {name}(BLOCK) {{
+    // Keeps the transactions sent to this address
+    let address = "0x0000000000000000000000000000000000000000";
+    let matches = [];
+    for tx in BLOCK.transactionTraces {{
+        if tx.to == address {{
+            matches.push(tx);
+        }}
+    }}
+    matches
+}}"#
+            ),
+            TemplateKind::EventDecoder => format!(
+                r#"fn {name}(BLOCK) {{
+    // Load an ABI named "erc20" in the ABI window, logs without a matching event are skipped
+    let logs = [];
+    for tx in BLOCK.transactionTraces {{
+        logs += tx.receipt.logs;
+    }}
+    let events = [];
+    for log in logs {{
+        try {{
+            events.push(decode_log("erc20", log));
+        }} catch {{}}
+    }}
+    events
+}}"#
+            ),
+            TemplateKind::CounterStore => format!(
+                r#"fn {name}(BLOCK, s) {{
+    // Adds this block's transactions to the running total
+    s.add("transactions", BLOCK.transactionTraces.len());
+}}"#
+            ),
+            TemplateKind::GraphOut => format!(
+                r#"fn {name}(BLOCK) {{
+    // One entity change per transaction, in the shape graph_out modules emit
+    let changes = [];
+    for tx in BLOCK.transactionTraces {{
+        let fields = [#{{ name: "from", value: tx.from }}, #{{ name: "to", value: tx.to }}];
+        changes.push(#{{ entity: "Transaction", id: tx.hash, operation: "CREATE", fields: fields }});
+    }}
+    #{{ entityChanges: changes }}
+}}"#
+            ),
+        };
+
+        let inputs = vec!["BLOCK".to_string()];
+        match kind {
+            TemplateKind::CounterStore => Module::Store {
+                name: name.to_string(),
+                code,
+                inputs,
+                update_policy: "add".to_string(),
+                editing: true,
+                last_modified: now(),
+                store_param: default_store_param(),
+                handler: None,
+                params: String::new(),
+            },
+            _ => Module::Map {
+                name: name.to_string(),
+                code,
+                inputs,
+                editing: true,
+                last_modified: now(),
+                handler: None,
+                params: String::new(),
+            },
+        }
     }
 
     pub fn build_default_modules() -> HashMap<i64, Self> {
@@ -141,6 +675,9 @@ impl Module {
                 code: "fn foo(BLOCK) {\n BLOCK.number \n}".to_string(),
                 inputs: vec!["BLOCK".to_string()],
                 editing: true,
+                last_modified: now(),
+                handler: None,
+                params: String::new(),
             },
         );
 
@@ -152,8 +689,393 @@ impl Module {
                 inputs: vec!["foo".to_string()],
                 update_policy: "set".to_string(),
                 editing: true,
+                last_modified: now(),
+                store_param: default_store_param(),
+                handler: None,
+                params: String::new(),
             },
         );
         map
     }
 }
+
+/// Whether the word appears in the text on its own, not as part of a longer identifier
+fn contains_word(text: &str, word: &str) -> bool {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(word).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.is_some_and(is_ident) && !after.is_some_and(is_ident)
+    })
+}
+
+/// Returns `base` if no module has that name, otherwise `base` with the first free numeric suffix, eg `base_2`
+pub fn unique_name(modules: &HashMap<i64, Module>, base: &str) -> String {
+    free_name(base, |name| {
+        modules.values().any(|module| module.name() == name)
+    })
+}
+
+/// Returns `base` if it isn't taken, otherwise `base` with the first free numeric suffix, eg `base_2`
+pub fn free_name(base: &str, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(base) {
+        return base.to_string();
+    }
+
+    (2..)
+        .map(|i| format!("{}_{}", base, i))
+        .find(|name| !taken(name))
+        .unwrap()
+}
+
+/// The text as a rhai string literal, escaping anything that would end it early
+fn rhai_string(text: &str) -> String {
+    let mut literal = String::with_capacity(text.len() + 2);
+    literal.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Renames a module, and updates the inputs of every module that used the old name.
+/// A handler named after the module is renamed in its code too, so it still matches
+pub fn rename_module(modules: &mut HashMap<i64, Module>, old: &str, new: &str) {
+    for module in modules.values_mut() {
+        let mut changed = false;
+
+        if module.name() == old {
+            if module.handler() == old {
+                let code =
+                    module
+                        .code()
+                        .replacen(&format!("fn {}(", old), &format!("fn {}(", new), 1);
+                *module.code_mut() = code;
+            }
+            *module.name_mut() = new.to_string();
+            changed = true;
+        }
+
+        for input in module.inputs_mut().iter_mut().filter(|input| *input == old) {
+            *input = new.to_string();
+            changed = true;
+        }
+
+        if changed {
+            module.touch();
+        }
+    }
+}
+
+/// Orders the module ids so every module comes after the modules it takes as inputs
+///
+/// Modules are visited by name, so the order doesn't depend on the random ids.
+/// If the modules depend on each other in a loop, the cycle is returned as a path of module names.
+pub fn dependency_order(modules: &HashMap<i64, Module>) -> Result<Vec<i64>, Vec<String>> {
+    enum Mark {
+        Visiting,
+        Done,
+    }
+
+    fn visit(
+        id: i64,
+        modules: &HashMap<i64, Module>,
+        by_name: &HashMap<&str, i64>,
+        marks: &mut HashMap<i64, Mark>,
+        stack: &mut Vec<i64>,
+        order: &mut Vec<i64>,
+    ) -> Result<(), Vec<String>> {
+        match marks.get(&id) {
+            Some(Mark::Done) => return Ok(()),
+            Some(Mark::Visiting) => {
+                let start = stack.iter().position(|other| *other == id).unwrap_or(0);
+                let cycle = stack[start..]
+                    .iter()
+                    .chain(std::iter::once(&id))
+                    .map(|id| modules[id].name().to_string())
+                    .collect();
+                return Err(cycle);
+            }
+            None => {}
+        }
+
+        marks.insert(id, Mark::Visiting);
+        stack.push(id);
+
+        // Inputs that aren't modules, like BLOCK, don't add any edges
+        for input in modules[&id].inputs() {
+            if let Some(input_id) = by_name.get(input.as_str()) {
+                visit(*input_id, modules, by_name, marks, stack, order)?;
+            }
+        }
+
+        stack.pop();
+        marks.insert(id, Mark::Done);
+        order.push(id);
+        Ok(())
+    }
+
+    let by_name = modules
+        .iter()
+        .map(|(id, module)| (module.name(), *id))
+        .collect::<HashMap<_, _>>();
+
+    let mut ids = modules.keys().copied().collect::<Vec<_>>();
+    ids.sort_by_key(|id| (modules[id].name(), *id));
+
+    let mut marks = HashMap::new();
+    let mut order = Vec::with_capacity(ids.len());
+    for id in ids {
+        visit(
+            id,
+            modules,
+            &by_name,
+            &mut marks,
+            &mut Vec::new(),
+            &mut order,
+        )?;
+    }
+
+    Ok(order)
+}
+
+/// The module with the id and every module it depends on, directly or not, in dependency order
+pub fn upstream_order(modules: &HashMap<i64, Module>, id: i64) -> Result<Vec<i64>, Vec<String>> {
+    let by_name = modules
+        .iter()
+        .map(|(id, module)| (module.name(), *id))
+        .collect::<HashMap<_, _>>();
+
+    let mut upstream = HashSet::from([id]);
+    let mut pending = vec![id];
+    while let Some(id) = pending.pop() {
+        for input in modules[&id].inputs() {
+            if let Some(input_id) = by_name.get(input.as_str()) {
+                if upstream.insert(*input_id) {
+                    pending.push(*input_id);
+                }
+            }
+        }
+    }
+
+    // Only the upstream modules are ordered, so a cycle elsewhere doesn't get in the way
+    let upstream = modules
+        .iter()
+        .filter(|(id, _)| upstream.contains(id))
+        .map(|(id, module)| (*id, module.clone()))
+        .collect();
+    dependency_order(&upstream)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A map taking the inputs, whose handler just returns 1
+    pub(crate) fn map(name: &str, inputs: &[&str]) -> Module {
+        Module::Map {
+            name: name.to_string(),
+            code: format!("fn {}({}) {{ 1 }}", name, inputs.join(", ")),
+            inputs: inputs.iter().map(|input| input.to_string()).collect(),
+            editing: false,
+            last_modified: 0,
+            handler: None,
+            params: String::new(),
+        }
+    }
+
+    fn modules(list: Vec<Module>) -> HashMap<i64, Module> {
+        list.into_iter().map(|module| (random(), module)).collect()
+    }
+
+    fn order_names(modules: &HashMap<i64, Module>) -> Vec<&str> {
+        dependency_order(modules)
+            .unwrap()
+            .iter()
+            .map(|id| modules[id].name())
+            .collect()
+    }
+
+    #[test]
+    fn dependency_order_puts_inputs_first() {
+        let modules = modules(vec![
+            map("c", &["b"]),
+            map("b", &["a"]),
+            map("a", &["BLOCK"]),
+        ]);
+        assert_eq!(order_names(&modules), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn renaming_a_map_updates_the_store_using_it() {
+        let mut totals = store("totals", "fn totals(counts, s) { s.set(counts) }");
+        *totals.inputs_mut() = vec!["counts".to_string()];
+        let mut modules = modules(vec![map("counts", &["BLOCK"]), totals]);
+        rename_module(&mut modules, "counts", "tallies");
+
+        let renamed = modules.values().find(|m| m.name() == "tallies").unwrap();
+        assert!(renamed.code().starts_with("fn tallies(BLOCK)"));
+        assert!(renamed.signature_in_sync());
+
+        let dependent = modules.values().find(|m| m.name() == "totals").unwrap();
+        assert_eq!(dependent.kind(), "store");
+        assert_eq!(dependent.inputs(), &["tallies"]);
+    }
+
+    #[test]
+    fn dependency_order_ignores_unknown_inputs() {
+        let modules = modules(vec![map("b", &["a", "missing"]), map("a", &["BLOCK"])]);
+        assert_eq!(order_names(&modules), ["a", "b"]);
+    }
+
+    #[test]
+    fn every_template_compiles() {
+        let engine = rhai::Engine::new();
+        for kind in TemplateKind::ALL {
+            let template = Module::template(kind);
+            if let Err(err) = engine.compile(template.code()) {
+                panic!("The {} template doesn't compile: {}", kind.label(), err);
+            }
+            assert!(template.signature_in_sync(), "{}", kind.label());
+        }
+    }
+
+    #[test]
+    fn unique_name_keeps_a_free_name() {
+        let modules = modules(vec![map("map_a", &["BLOCK"])]);
+        assert_eq!(unique_name(&modules, "map_b"), "map_b");
+    }
+
+    #[test]
+    fn unique_name_takes_the_first_free_suffix() {
+        let modules = modules(vec![
+            map("map_a", &["BLOCK"]),
+            map("map_a_2", &["BLOCK"]),
+            map("map_a_4", &["BLOCK"]),
+        ]);
+        assert_eq!(unique_name(&modules, "map_a"), "map_a_3");
+    }
+
+    #[test]
+    fn registering_an_unknown_input_is_an_error() {
+        let modules = modules(vec![map("map_a", &["BLOCK", "bogus"])]);
+        let module = modules.values().next().unwrap();
+
+        let err = module
+            .register_module(&modules, "sf.ethereum.type.v2.Block")
+            .unwrap_err();
+        assert_eq!(err, "Module `map_a`: Unknown input: bogus");
+    }
+
+    #[test]
+    fn every_update_policy_survives_saving_and_registering() {
+        for policy in Module::valid_update_policies() {
+            let store = map("store_a", &["BLOCK"]).convert_to_store(policy.to_string());
+
+            let saved = serde_json::to_string(&store).unwrap();
+            assert!(serde_json::from_str::<Module>(&saved).unwrap() == store);
+
+            let modules = modules(vec![store.clone()]);
+            let code = store
+                .register_module(&modules, "sf.ethereum.type.v2.Block")
+                .unwrap();
+            assert!(
+                code.contains(&format!("updatePolicy: \"{}\"", policy)),
+                "{}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn names_must_be_rhai_identifiers() {
+        assert!(Module::validate_name("map_transfers_2").is_ok());
+        assert!(Module::validate_name("_private").is_ok());
+
+        assert!(Module::validate_name("").is_err());
+        assert!(Module::validate_name("2fast").is_err());
+        assert!(Module::validate_name("map-transfers").is_err());
+        assert!(Module::validate_name("map transfers").is_err());
+    }
+
+    #[test]
+    fn names_cant_be_reserved_inputs_or_keywords() {
+        assert!(Module::validate_name("BLOCK").is_err());
+        assert!(Module::validate_name("PARAMS").is_err());
+        assert!(Module::validate_name("fn").is_err());
+        assert!(Module::validate_name("let").is_err());
+    }
+
+    #[test]
+    fn expected_signature_takes_the_inputs() {
+        let module = map("map_a", &["BLOCK", "PARAMS"]);
+        assert_eq!(module.expected_signature(), "fn map_a(BLOCK, PARAMS)");
+    }
+
+    #[test]
+    fn expected_signature_of_a_store_ends_with_the_store() {
+        let store = map("store_a", &["map_a"]).convert_to_store("add".to_string());
+        assert_eq!(store.expected_signature(), "fn store_a(map_a, s)");
+    }
+
+    #[test]
+    fn source_map_names_the_module_a_line_is_in() {
+        let mut source_map = SourceMap::default();
+        source_map.push(1, "map_a");
+        source_map.push(8, "map_b");
+
+        assert_eq!(source_map.module_at(1), Some("map_a"));
+        assert_eq!(source_map.module_at(7), Some("map_a"));
+        assert_eq!(source_map.module_at(8), Some("map_b"));
+        assert_eq!(source_map.module_at(100), Some("map_b"));
+        assert_eq!(source_map.module_at(0), None);
+    }
+
+    fn store(name: &str, code: &str) -> Module {
+        Module::Store {
+            name: name.to_string(),
+            code: code.to_string(),
+            inputs: vec!["map_a".to_string()],
+            update_policy: "set".to_string(),
+            editing: false,
+            last_modified: 0,
+            store_param: default_store_param(),
+            handler: None,
+            params: String::new(),
+        }
+    }
+
+    #[test]
+    fn lint_passes_a_store_that_writes() {
+        let store = store("store_a", "fn store_a(map_a, s) { s.set(map_a) }");
+        assert!(store.lint().is_empty());
+    }
+
+    #[test]
+    fn lint_warns_when_the_store_is_never_used() {
+        // `s` only appears in the signature and inside another word
+        let store = store("store_a", "fn store_a(map_a, s) { let sum = map_a; }");
+        let warnings = store.lint();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("`s` is never used"), "{}", warnings[0]);
+    }
+
+    #[test]
+    fn lint_warns_about_a_misnamed_handler() {
+        let mut module = map("map_a", &["BLOCK"]);
+        if let Module::Map { code, .. } = &mut module {
+            *code = "fn map_b(BLOCK) { 1 }".to_string();
+        }
+        let warnings = module.lint();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("named `map_b`"), "{}", warnings[0]);
+    }
+}
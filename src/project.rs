@@ -0,0 +1,82 @@
+//! Saves the editor's project to a standalone json file and loads it back, so a project can be shared or backed up
+
+use std::{collections::HashMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{block_cache::BlockCache, modules::Module, EditorConfig, UserConfig};
+
+/// Bumped whenever the project format changes in a way older files can't be read as
+pub const PROJECT_VERSION: u64 = 1;
+
+/// What the project window or menu asked for, run once the frame is done with the editor state
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProjectAction {
+    Export,
+    Import,
+    /// Starts over from the default modules and config
+    New,
+}
+
+impl ProjectAction {
+    /// Whether the action replaces the current project, so unsaved changes would be lost
+    pub fn discards_changes(&self) -> bool {
+        match self {
+            ProjectAction::Export => false,
+            ProjectAction::Import | ProjectAction::New => true,
+        }
+    }
+}
+
+/// The state written to a project file, borrowed so exporting doesn't need to clone it
+#[derive(Serialize)]
+pub struct ProjectRef<'a> {
+    pub version: u64,
+    pub modules: &'a HashMap<i64, Module>,
+    pub abis: &'a HashMap<String, String>,
+    pub user_config: &'a UserConfig,
+    pub editor_config: &'a EditorConfig,
+    pub block_cache: &'a BlockCache,
+}
+
+/// A project read back from a file
+#[derive(Deserialize)]
+pub struct Project {
+    pub version: u64,
+    pub modules: HashMap<i64, Module>,
+    pub abis: HashMap<String, String>,
+    pub user_config: UserConfig,
+    pub editor_config: EditorConfig,
+    pub block_cache: BlockCache,
+}
+
+pub fn export(path: &Path, project: &ProjectRef) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(project).map_err(|err| err.to_string())?;
+    fs::write(path, json).map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+/// Reads a project file, rejecting files written by a different project version
+pub fn import(path: &Path) -> Result<Project, String> {
+    let json = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    let value: Value = serde_json::from_str(&json)
+        .map_err(|err| format!("{} isn't valid json: {}", path.display(), err))?;
+
+    // Check the version before the rest, so an incompatible file gets a clear error instead of a missing field
+    match value.get("version").and_then(Value::as_u64) {
+        Some(PROJECT_VERSION) => {}
+        Some(version) => {
+            return Err(format!(
+                "{} is a version {} project, only version {} is supported",
+                path.display(),
+                version,
+                PROJECT_VERSION
+            ))
+        }
+        None => return Err(format!("{} isn't a project file", path.display())),
+    }
+
+    serde_json::from_value(value)
+        .map_err(|err| format!("{} isn't a valid project: {}", path.display(), err))
+}
@@ -0,0 +1,128 @@
+//! Display only masking of json values, for sharing output without leaking data
+
+use serde_json::Value;
+
+const MASK: &str = "***";
+
+/// What to mask in displayed json, kept for the current session only
+#[derive(Default)]
+pub struct Redaction {
+    pub enabled: bool,
+    /// Dotted key paths to mask, one per line. `*` matches any key or array index, eg `transactions.*.from`
+    pub paths: String,
+    /// Masks any string that looks like a 20 byte hex address
+    pub mask_addresses: bool,
+    /// Masks numbers, including the numeric strings proto3 json uses for 64 bit integers
+    pub mask_numbers: bool,
+}
+
+impl Redaction {
+    /// Returns a copy of the value with the configured fields masked
+    pub fn apply(&self, value: &Value) -> Value {
+        let paths = self
+            .paths
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| line.split('.').collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        let mut value = value.clone();
+        self.redact(&mut value, &mut Vec::new(), &paths);
+        value
+    }
+
+    fn redact(&self, value: &mut Value, path: &mut Vec<String>, paths: &[Vec<&str>]) {
+        if paths.iter().any(|pattern| path_matches(pattern, path)) {
+            *value = Value::String(MASK.to_string());
+            return;
+        }
+
+        match value {
+            Value::Object(obj) => {
+                for (key, value) in obj.iter_mut() {
+                    path.push(key.clone());
+                    self.redact(value, path, paths);
+                    path.pop();
+                }
+            }
+            Value::Array(arr) => {
+                for (i, value) in arr.iter_mut().enumerate() {
+                    path.push(i.to_string());
+                    self.redact(value, path, paths);
+                    path.pop();
+                }
+            }
+            Value::Number(_) if self.mask_numbers => *value = Value::String(MASK.to_string()),
+            Value::String(s) if self.should_mask_string(s) => *s = MASK.to_string(),
+            _ => {}
+        }
+    }
+
+    fn should_mask_string(&self, s: &str) -> bool {
+        (self.mask_addresses && is_address(s))
+            || (self.mask_numbers && !s.is_empty() && s.chars().all(|c| c.is_ascii_digit()))
+    }
+}
+
+fn path_matches(pattern: &[&str], path: &[String]) -> bool {
+    pattern.len() == path.len()
+        && pattern
+            .iter()
+            .zip(path)
+            .all(|(pattern, key)| *pattern == "*" || pattern == key)
+}
+
+fn is_address(s: &str) -> bool {
+    let hex = s.strip_prefix("0x").unwrap_or(s);
+    hex.len() == 40 && hex.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn paths_mask_matching_fields_with_wildcards() {
+        let redaction = Redaction {
+            enabled: true,
+            paths: "transactions.*.from\n\nblock".to_string(),
+            ..Redaction::default()
+        };
+        let value = json!({
+            "block": { "number": 1 },
+            "transactions": [{ "from": "alice", "to": "bob" }, { "from": "carol" }],
+        });
+
+        assert_eq!(
+            redaction.apply(&value),
+            json!({
+                "block": MASK,
+                "transactions": [{ "from": MASK, "to": "bob" }, { "from": MASK }],
+            })
+        );
+    }
+
+    #[test]
+    fn addresses_and_numbers_are_masked_anywhere() {
+        let redaction = Redaction {
+            enabled: true,
+            mask_addresses: true,
+            mask_numbers: true,
+            ..Redaction::default()
+        };
+        let value = json!({
+            "owner": "0x00000000219ab540356cbb839cbe05303d7705fa",
+            "balance": "1000000",
+            "logIndex": 3,
+            "symbol": "WETH",
+        });
+
+        assert_eq!(
+            redaction.apply(&value),
+            json!({ "owner": MASK, "balance": MASK, "logIndex": MASK, "symbol": "WETH" })
+        );
+    }
+}
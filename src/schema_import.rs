@@ -0,0 +1,217 @@
+//! Scaffolds modules from a subgraph `schema.graphql`
+//!
+//! This is a best effort importer, it only understands `type Foo @entity { ... }` definitions.
+//! For each entity we create a map module from the BLOCK and a store that keeps its entities by id,
+//! and a `graph_out` map that takes all of the maps as inputs.
+//! The generated handlers are stubs the user is expected to fill in.
+
+use crate::modules::{default_store_param, now, Module};
+
+/// The update policy of the generated stores, which keep the latest version of each entity
+const STORE_UPDATE_POLICY: &str = "set";
+
+/// An entity type found in a graphql schema
+pub struct Entity {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+/// Finds the `@entity` types in a graphql schema
+pub fn parse_entities(schema: &str) -> Vec<Entity> {
+    // Strip comments so they can't hide braces or keywords
+    let schema = schema
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut entities = Vec::new();
+    let mut rest = schema.as_str();
+
+    while let Some(start) = find_keyword(rest, "type") {
+        rest = &rest[start + "type".len()..];
+
+        let (Some(open), Some(close)) = (rest.find('{'), rest.find('}')) else {
+            break;
+        };
+        if close < open {
+            rest = &rest[close + 1..];
+            continue;
+        }
+
+        let header = &rest[..open];
+        let body = &rest[open + 1..close];
+        rest = &rest[close + 1..];
+
+        if !header.contains("@entity") {
+            continue;
+        }
+
+        let Some(name) = header.split_whitespace().next() else {
+            continue;
+        };
+
+        let fields = body
+            .split(['\n', ','])
+            .filter_map(|field| {
+                let end = field.find([':', '('])?;
+                let name = field[..end].trim();
+                (!name.is_empty()).then(|| name.to_string())
+            })
+            .collect();
+
+        entities.push(Entity {
+            name: name.to_string(),
+            fields,
+        });
+    }
+
+    entities
+}
+
+/// Finds a keyword that isn't part of a longer identifier
+fn find_keyword(source: &str, keyword: &str) -> Option<usize> {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+
+    source.match_indices(keyword).map(|(i, _)| i).find(|&i| {
+        let before = source[..i].chars().next_back();
+        let after = source[i + keyword.len()..].chars().next();
+        !before.is_some_and(is_ident) && !after.is_some_and(is_ident)
+    })
+}
+
+/// Converts a type name like `TokenPair` into `token_pair`
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
+
+/// Creates a map module and a store per entity, plus a `graph_out` module that takes all of the maps as inputs
+pub fn scaffold_modules(entities: &[Entity]) -> Vec<Module> {
+    let mut modules = Vec::new();
+    let mut map_names = Vec::new();
+
+    for entity in entities {
+        let name = format!("map_{}", snake_case(&entity.name));
+        let code = format!(
+            "fn {name}(BLOCK) {{\n    // TODO: build {} entities from the block\n    // fields: {}\n    []\n}}",
+            entity.name,
+            entity.fields.join(", ")
+        );
+
+        modules.push(Module::Map {
+            name: name.clone(),
+            code,
+            inputs: vec!["BLOCK".to_string()],
+            editing: false,
+            last_modified: now(),
+            handler: None,
+            params: String::new(),
+        });
+
+        let store_name = format!("store_{}", snake_case(&entity.name));
+        let store_param = default_store_param();
+        modules.push(Module::Store {
+            name: store_name.clone(),
+            code: format!(
+                "fn {store_name}({name}, {store_param}) {{\n    // Keeps the latest version of each {} by its id\n    for entity in {name} {{\n        {store_param}.set(entity.id, entity);\n    }}\n}}",
+                entity.name
+            ),
+            inputs: vec![name.clone()],
+            update_policy: STORE_UPDATE_POLICY.to_string(),
+            editing: false,
+            last_modified: now(),
+            store_param,
+            handler: None,
+            params: String::new(),
+        });
+        map_names.push(name);
+    }
+
+    if !map_names.is_empty() {
+        let params = map_names.join(", ");
+        modules.push(Module::Map {
+            name: "graph_out".to_string(),
+            code: format!(
+                "fn graph_out({params}) {{\n    // TODO: turn the entities into entity changes\n    [{params}]\n}}"
+            ),
+            inputs: map_names,
+            editing: false,
+            last_modified: now(),
+            handler: None,
+            params: String::new(),
+        });
+    }
+
+    modules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"
+# type Commented @entity { id: ID! }
+type TokenPair @entity {
+  id: ID!
+  token0: Token!, token1: Token!
+  swaps(first: Int): [Swap!]!
+}
+
+type NotAnEntity {
+  id: ID!
+}
+
+type Swap @entity(immutable: true) {
+  id: ID!
+  amount: BigInt!
+}
+"#;
+
+    #[test]
+    fn parse_entities_finds_entity_types_and_fields() {
+        let entities = parse_entities(SCHEMA);
+
+        let names = entities.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["TokenPair", "Swap"]);
+        assert_eq!(entities[0].fields, ["id", "token0", "token1", "swaps"]);
+        assert_eq!(entities[1].fields, ["id", "amount"]);
+    }
+
+    #[test]
+    fn scaffold_makes_a_map_and_store_per_entity_and_a_graph_out() {
+        let modules = scaffold_modules(&parse_entities(SCHEMA));
+
+        let names = modules.iter().map(Module::name).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "map_token_pair",
+                "store_token_pair",
+                "map_swap",
+                "store_swap",
+                "graph_out"
+            ]
+        );
+
+        let store = &modules[1];
+        assert!(
+            matches!(store, Module::Store { update_policy, .. } if update_policy == STORE_UPDATE_POLICY)
+        );
+        assert_eq!(store.inputs(), &["map_token_pair"]);
+        assert!(store.signature_in_sync());
+        assert!(store.lint().is_empty());
+
+        assert_eq!(modules[4].inputs(), &["map_token_pair", "map_swap"]);
+    }
+}
@@ -0,0 +1,172 @@
+//! Downloads substreams packages into a local cache, and reads the module names out of them
+//! so output modules can be picked from a list
+//!
+//! A package is a protobuf `sf.substreams.v1.Package`. Only the module names are needed,
+//! so this walks the protobuf wire format directly instead of pulling in the generated types.
+
+use std::{env, fs, path::PathBuf};
+
+use tiny_keccak::{Hasher, Keccak};
+
+use crate::APP_ID;
+
+/// `Package.modules`, a `Modules` message
+const PACKAGE_MODULES: u64 = 6;
+/// `Modules.modules`, the repeated `Module` messages
+const MODULES_MODULES: u64 = 1;
+/// `Module.name`
+const MODULE_NAME: u64 = 1;
+
+pub fn is_url(package_file: &str) -> bool {
+    package_file.starts_with("http://") || package_file.starts_with("https://")
+}
+
+/// The directory downloaded packages are kept in, the temp dir if the platform has no data dir
+pub fn cache_dir() -> PathBuf {
+    eframe::storage_dir(APP_ID)
+        .unwrap_or_else(|| env::temp_dir().join("substreams-editor"))
+        .join("spkg")
+}
+
+/// Where the package at the url is cached, named by a hash of the url so any url makes a valid file name
+pub fn cache_path(url: &str) -> PathBuf {
+    let mut hash = [0u8; 32];
+    let mut keccak = Keccak::v256();
+    keccak.update(url.as_bytes());
+    keccak.finalize(&mut hash);
+
+    let name: String = hash[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    cache_dir().join(format!("{}.spkg", name))
+}
+
+/// Downloads the package at the url into its cache path
+///
+/// The download is written to a `.part` file first and only renamed once it's complete,
+/// so a failed or interrupted download never leaves a truncated package in the cache.
+pub async fn download(url: &str) -> Result<PathBuf, String> {
+    let path = cache_path(url);
+    let part = path.with_extension("spkg.part");
+    let failed = |err: &dyn std::fmt::Display| format!("Failed to download {}: {}", url, err);
+
+    let response = reqwest::get(url).await.map_err(|err| failed(&err))?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "Failed to download {}: the server responded with {}",
+            url,
+            response.status()
+        ));
+    }
+    let bytes = response.bytes().await.map_err(|err| failed(&err))?;
+
+    fs::create_dir_all(cache_dir()).map_err(|err| failed(&err))?;
+    fs::write(&part, &bytes).map_err(|err| {
+        let _ = fs::remove_file(&part);
+        failed(&err)
+    })?;
+    fs::rename(&part, &path).map_err(|err| failed(&err))?;
+    Ok(path)
+}
+
+/// Reads a package from a local path, the caller downloads urls first
+pub fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))
+}
+
+/// The names of the modules in an encoded package, in the order the package lists them
+pub fn module_names(package: &[u8]) -> Result<Vec<String>, String> {
+    let mut names = Vec::new();
+    for modules in length_delimited(package, PACKAGE_MODULES)? {
+        for module in length_delimited(modules, MODULES_MODULES)? {
+            for name in length_delimited(module, MODULE_NAME)? {
+                let name = String::from_utf8(name.to_vec())
+                    .map_err(|_| "A module name isn't valid utf8".to_string())?;
+                names.push(name);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// The contents of every length delimited field with the number in a message, skipping the other fields
+fn length_delimited(mut message: &[u8], field: u64) -> Result<Vec<&[u8]>, String> {
+    let mut values = Vec::new();
+    while !message.is_empty() {
+        let key = read_varint(&mut message)?;
+        let len = match key & 0b111 {
+            0 => {
+                read_varint(&mut message)?;
+                0
+            }
+            1 => 8,
+            2 => read_varint(&mut message)? as usize,
+            5 => 4,
+            wire_type => return Err(format!("Unsupported protobuf wire type {}", wire_type)),
+        };
+
+        if len > message.len() {
+            return Err("The package is truncated".to_string());
+        }
+        let (value, rest) = message.split_at(len);
+        if key >> 3 == field && key & 0b111 == 2 {
+            values.push(value);
+        }
+        message = rest;
+    }
+    Ok(values)
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| "The package is truncated".to_string())?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("The package has an invalid varint".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A length delimited protobuf field, for fields and values short enough for single byte varints
+    fn field(number: u64, value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![(number << 3 | 2) as u8, value.len() as u8];
+        encoded.extend_from_slice(value);
+        encoded
+    }
+
+    fn module(name: &str) -> Vec<u8> {
+        let mut module = field(MODULE_NAME, name.as_bytes());
+        // `Module.binary_index`, a varint that has to be skipped
+        module.extend_from_slice(&[4 << 3, 0x96, 0x01]);
+        module
+    }
+
+    #[test]
+    fn module_names_are_read_in_order() {
+        let mut modules = field(MODULES_MODULES, &module("map_transfers"));
+        modules.extend(field(MODULES_MODULES, &module("store_balances")));
+        let mut package = field(1, b"skipped proto file");
+        package.extend(field(PACKAGE_MODULES, &modules));
+
+        assert_eq!(
+            module_names(&package).unwrap(),
+            ["map_transfers", "store_balances"]
+        );
+    }
+
+    #[test]
+    fn truncated_packages_are_an_error() {
+        let package = field(PACKAGE_MODULES, &field(MODULES_MODULES, &module("map_a")));
+        assert!(module_names(&package[..package.len() - 3]).is_err());
+    }
+}
@@ -5,23 +5,92 @@
 //! 2. The stream thread, which is a thread that runs the substreams engine
 //! 3. The worker thread, which is a thread that runs rhai scripts
 
-use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use std::{
+    fmt,
+    path::PathBuf,
+    sync::mpsc::Sender,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{ser::SerializeStructVariant, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{modules::SourceMap, webhook::WebhookConfig};
+
+/// The senders to each thread, passed around together by the panels that message them
+#[derive(Clone, Copy)]
+pub struct Channels<'a> {
+    pub gui: &'a Sender<GuiMessage>,
+    pub worker: &'a Sender<WorkerMessage>,
+    pub stream: &'a UnboundedSender<StreamMessages>,
+}
 
 /// Messages that can be sent to the worker thread
 pub enum WorkerMessage {
+    /// Evaluates the code, keeping the functions from earlier evals
     Eval(String),
-    EvalWithArgs(String, Vec<Value>),
+    /// Evaluates the full source, replacing the functions from earlier evals
+    EvalFresh(String, SourceMap),
+    /// Calls the module's handler with the args, as the module name, handler name and whether it's a store,
+    /// which is passed a local store to write to after the args
+    EvalWithArgs(String, String, Vec<Value>, bool),
     Reset,
     Build,
+    /// Compiles the full source without evaluating it
+    Check(String, SourceMap),
+    /// Sets the max function call depth of the engine
+    SetMaxCallLevels(usize),
+    /// Keeps a stream output under a name, so it can be used as a module input
+    StoreOutput(String, Value),
+    /// Calls the module's handler with the named stored output in place of its input,
+    /// as the module name, handler name and output name
+    EvalWithOutput(String, String, String),
+    /// Adds an ABI json under a name, for handlers to decode with
+    LoadAbi(String, String),
+    /// Calls each step's handler in order on the block, feeding every output to the steps after it
+    EvalWithDeps(Vec<DepStep>, Value),
+}
+
+/// A module to run as part of a `WorkerMessage::EvalWithDeps`
+pub struct DepStep {
+    /// The module's name, which the steps after it take its output as
+    pub name: String,
+    pub handler: String,
+    pub inputs: Vec<String>,
+    /// What a `PARAMS` input is passed as
+    pub params: String,
+    /// Stores are passed a local store after their inputs, and output what was written to it
+    pub store: bool,
 }
 
 /// Messages that can be sent to the gui thread
 pub enum GuiMessage {
     PushMessage(String),
-    PushJson(String),
-    SetBlock(u8, String),
+    /// A failure, shown as an error in the messages panel
+    PushError(String),
+    PushJson(Value),
+    /// The outcome of a build
+    PushBuild(BuildResult),
+    /// The json result of a module's handler, with the module name and handler name.
+    /// Handlers can be shared, so the module name is what the result is kept under
+    PushResult(String, String, Value),
+    /// A formatted `RunSummary` for a completed stream
+    RunReport(String),
+    /// The result of a `WorkerMessage::Check`, with the error if the code didn't compile
+    CheckResult(Result<(), String>),
+    SetBlock(usize, String),
+    /// The chain head found by a `StreamMessages::GetLatestBlock`
+    SetLatestBlock(u64),
     ClearMessages,
+    /// How far the stream with the task id has got, or None once it's done
+    SetProgress(u64, Option<StreamProgress>),
+    /// The module names in the package with the given url or path
+    SetPackageModules(String, Vec<String>),
+    /// A stream task that can be stopped started, with what it's running, or finished with None
+    SetRunning(u64, Option<String>),
+    /// A message from a stream task, with the label of the stream it came from
+    Tagged(String, Box<GuiMessage>),
 }
 
 pub enum StreamMessages {
@@ -31,20 +100,344 @@ pub enum StreamMessages {
         api_key: String,
         package_file: String,
         endpoint: String,
-        module_name: String,
+        /// The output modules to stream, one after another
+        module_names: Vec<String>,
+        /// Where to post the outputs, if anywhere
+        webhook: Option<WebhookConfig>,
     },
 
     GetBlock {
         number: i64,
         api_key: String,
         endpoint: String,
-        cache_slot: u8,
+        cache_slot: usize,
+    },
+
+    /// Fetches `count` blocks from `start` into the slots from `first_slot` on, one block per slot
+    GetBlockRange {
+        start: i64,
+        count: u64,
+        api_key: String,
+        endpoint: String,
+        first_slot: usize,
     },
+
+    /// Finds the current chain head, for the block cache's block number
+    GetLatestBlock { api_key: String, endpoint: String },
+
+    /// Reads the module names out of a package, for the output module lists
+    ListModules { package_file: String },
+
+    /// Sets how long to wait for a stream to connect before giving up
+    SetTimeout(Duration),
+
+    /// Sets how many streams and block fetches can run at once
+    SetConcurrency(usize),
+
+    /// Stops the stream or block range fetch with the id, or all of them if there's no id.
+    /// Tasks still waiting for their turn stop as soon as they start
+    Cancel(Option<u64>),
+}
+
+impl StreamMessages {
+    /// What the task is doing, for the list of tasks that can be stopped, or None if it can't be stopped
+    pub fn running_label(&self) -> Option<String> {
+        match self {
+            StreamMessages::Run {
+                start,
+                stop,
+                module_names,
+                ..
+            } => {
+                let range = if *stop == 0 {
+                    format!("live from {}", start)
+                } else {
+                    format!("{} to {}", start, stop)
+                };
+                Some(format!("{} {}", module_names.join(", "), range))
+            }
+            StreamMessages::GetBlockRange { start, count, .. } => Some(format!(
+                "blocks {} to {}",
+                start,
+                *start + *count as i64 - 1
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// A message in the messages panel
+pub struct Message {
+    /// Which stream the message came from, shown as a badge
+    pub source: Option<String>,
+    /// When the message was pushed, or loaded for messages from an earlier session
+    pub time: SystemTime,
+    pub kind: MessageKind,
+}
+
+impl From<MessageKind> for Message {
+    fn from(kind: MessageKind) -> Self {
+        Self {
+            source: None,
+            time: SystemTime::now(),
+            kind,
+        }
+    }
+}
+
+impl Message {
+    /// When the message was pushed, in milliseconds since the unix epoch
+    fn unix_millis(&self) -> u64 {
+        self.time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64)
+    }
+
+    /// The message as one line of an exported log, with its time in milliseconds since the unix epoch
+    pub fn log_entry(&self) -> Value {
+        let time = self.unix_millis();
+        let (kind, message) = match &self.kind {
+            MessageKind::JsonMessage(json) => ("json", json.clone()),
+            MessageKind::TextMessage(msg) => ("text", Value::from(msg.as_str())),
+            MessageKind::ErrorMessage(msg) => ("error", Value::from(msg.as_str())),
+            MessageKind::BuildMessage(build) => {
+                ("build", serde_json::to_value(build).unwrap_or_default())
+            }
+        };
+        json!({ "time": time, "source": self.source, "kind": kind, "message": message })
+    }
+}
+
+/// How a message is saved. Messages used to be saved as just their kind, so those variants are kept to load them,
+/// at the time they're loaded. It's an enum rather than an untagged either-or, since the state is saved as RON
+#[derive(Deserialize)]
+enum SavedMessage {
+    JsonMessage(Value),
+    TextMessage(String),
+    ErrorMessage(String),
+    BuildMessage(BuildResult),
+    /// The time is in milliseconds since the unix epoch
+    Timed {
+        time: u64,
+        kind: MessageKind,
+    },
+}
+
+// The source isn't saved, since stream ids only mean something in the session that made them
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut saved = serializer.serialize_struct_variant("SavedMessage", 4, "Timed", 2)?;
+        saved.serialize_field("time", &self.unix_millis())?;
+        saved.serialize_field("kind", &self.kind)?;
+        saved.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let message = match SavedMessage::deserialize(deserializer)? {
+            SavedMessage::JsonMessage(json) => MessageKind::JsonMessage(json).into(),
+            SavedMessage::TextMessage(msg) => MessageKind::TextMessage(msg).into(),
+            SavedMessage::ErrorMessage(msg) => MessageKind::ErrorMessage(msg).into(),
+            SavedMessage::BuildMessage(build) => MessageKind::BuildMessage(build).into(),
+            SavedMessage::Timed { time, kind } => Message {
+                source: None,
+                time: UNIX_EPOCH + Duration::from_millis(time),
+                kind,
+            },
+        };
+        Ok(message)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum MessageKind {
     JsonMessage(Value),
     TextMessage(String),
-    //ErrorMessage(String),
+    ErrorMessage(String),
+    BuildMessage(BuildResult),
+}
+
+/// The outcome of a `WorkerMessage::Build`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BuildResult {
+    pub success: bool,
+    /// Where the package was written, when the build reported a path that exists
+    pub path: Option<PathBuf>,
+    /// What `codegen()` returned, or the error it failed with
+    pub log: String,
+}
+
+impl MessageKind {
+    /// Errors and failed builds, the messages kept by Only Errors
+    pub fn is_error(&self) -> bool {
+        match self {
+            MessageKind::ErrorMessage(_) => true,
+            MessageKind::BuildMessage(build) => !build.success,
+            MessageKind::JsonMessage(_) | MessageKind::TextMessage(_) => false,
+        }
+    }
+
+    /// Whether the message contains the search term, ignoring case. `term` must already be lowercase
+    pub fn matches(&self, term: &str) -> bool {
+        match self {
+            MessageKind::JsonMessage(json) => json_contains(json, term),
+            MessageKind::TextMessage(msg) | MessageKind::ErrorMessage(msg) => {
+                msg.to_lowercase().contains(term)
+            }
+            MessageKind::BuildMessage(build) => {
+                let path = build.path.as_ref().map(|path| path.display().to_string());
+                build.log.to_lowercase().contains(term)
+                    || path.is_some_and(|path| path.to_lowercase().contains(term))
+            }
+        }
+    }
+}
+
+/// Whether any key or value in the json contains the lowercase term
+fn json_contains(json: &Value, term: &str) -> bool {
+    match json {
+        Value::Null => false,
+        Value::Bool(b) => b.to_string().contains(term),
+        Value::Number(n) => n.to_string().contains(term),
+        Value::String(s) => s.to_lowercase().contains(term),
+        Value::Array(arr) => arr.iter().any(|value| json_contains(value, term)),
+        Value::Object(obj) => obj
+            .iter()
+            .any(|(key, value)| key.to_lowercase().contains(term) || json_contains(value, term)),
+    }
+}
+
+/// How far a running stream has got
+#[derive(Clone)]
+pub struct StreamProgress {
+    pub start: i64,
+    pub stop: u64,
+    /// The block number of the last output, if the outputs have one
+    pub block: Option<u64>,
+    pub outputs: usize,
+}
+
+impl StreamProgress {
+    /// How much of the range has been streamed, or None when it can't be known,
+    /// because the stream is open ended, starts relative to the chain head, or the outputs have no block number
+    pub fn fraction(&self) -> Option<f32> {
+        let block = self.block?;
+        if self.stop == 0 || self.start < 0 {
+            return None;
+        }
+
+        let start = self.start as u64;
+        let total = self.stop.saturating_sub(start).max(1);
+        Some((block.saturating_sub(start) as f32 / total as f32).min(1.0))
+    }
+}
+
+impl fmt::Display for StreamProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.block {
+            Some(block) if self.stop != 0 => write!(f, "Block {} of {}", block, self.stop)?,
+            Some(block) => write!(f, "Block {}", block)?,
+            None => write!(f, "Streaming")?,
+        }
+        write!(f, ", {} outputs", self.outputs)
+    }
+}
+
+/// A summary of a completed stream run
+pub struct RunSummary {
+    pub module_name: String,
+    pub package_file: String,
+    pub endpoint: String,
+    pub start: i64,
+    pub stop: u64,
+    pub outputs: usize,
+    /// Outputs that weren't valid json, and webhook posts that failed
+    pub errors: usize,
+    /// The block number of the last output that had one
+    pub last_block: Option<u64>,
+    pub duration: Duration,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.duration.as_secs_f64();
+
+        writeln!(f, "Run Report")?;
+        writeln!(f, "Module: {}", self.module_name)?;
+        writeln!(f, "Package: {}", self.package_file)?;
+        writeln!(f, "Endpoint: {}", self.endpoint)?;
+        if self.stop == 0 {
+            writeln!(f, "Range: live from {}", self.start)?;
+        } else {
+            writeln!(f, "Range: {} to {}", self.start, self.stop)?;
+        }
+        writeln!(f, "Duration: {:.1}s", seconds)?;
+        // A negative start is relative to the chain head, so we don't know how many blocks that was.
+        // The rate is taken from the last block reached, since a cancelled run stops short of `stop`
+        if let Some(last_block) = self.last_block {
+            if self.start >= 0 && last_block >= self.start as u64 && seconds > 0.0 {
+                let blocks = last_block + 1 - self.start as u64;
+                writeln!(f, "Blocks/sec: {:.1}", blocks as f64 / seconds)?;
+            }
+        }
+        writeln!(f, "Outputs: {}", self.outputs)?;
+        write!(f, "Errors: {}", self.errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(last_block: Option<u64>) -> RunSummary {
+        RunSummary {
+            module_name: "map_transfers".to_string(),
+            package_file: "package.spkg".to_string(),
+            endpoint: "https://mainnet.eth.streamingfast.io:443".to_string(),
+            start: 100,
+            stop: 1100,
+            outputs: 3,
+            errors: 2,
+            last_block,
+            duration: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn blocks_per_second_counts_up_to_the_last_block_reached() {
+        // Cancelled 100 blocks into a 1000 block range
+        let report = summary(Some(199)).to_string();
+        assert!(report.contains("Blocks/sec: 10.0"), "{}", report);
+    }
+
+    #[test]
+    fn blocks_per_second_is_left_out_without_a_block_number() {
+        let report = summary(None).to_string();
+        assert!(!report.contains("Blocks/sec"), "{}", report);
+    }
+
+    #[test]
+    fn messages_saved_as_just_their_kind_still_load() {
+        let message: Message = serde_json::from_str(r#"{"TextMessage":"hello"}"#).unwrap();
+        assert!(matches!(message.kind, MessageKind::TextMessage(msg) if msg == "hello"));
+    }
+
+    #[test]
+    fn saved_messages_keep_their_time() {
+        let mut message = Message::from(MessageKind::ErrorMessage("failed".to_string()));
+        message.time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+
+        let saved = serde_json::to_string(&message).unwrap();
+        let loaded: Message = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.time, message.time);
+        assert!(matches!(loaded.kind, MessageKind::ErrorMessage(msg) if msg == "failed"));
+    }
+
+    #[test]
+    fn report_includes_the_error_count() {
+        let report = summary(Some(199)).to_string();
+        assert!(report.contains("Outputs: 3"), "{}", report);
+        assert!(report.contains("Errors: 2"), "{}", report);
+    }
 }
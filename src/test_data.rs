@@ -0,0 +1,91 @@
+//! Generates synthetic blocks for testing handlers without fetching from an endpoint
+//!
+//! The shape follows the json of an Ethereum `sf.ethereum.type.v2.Block`, like the ones the block cache fetches.
+//! Only the commonly used fields are filled in, and this is Ethereum specific.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// The parameters for a generated block
+#[derive(Serialize, Deserialize)]
+pub struct BlockParams {
+    pub number: u64,
+    pub transactions: usize,
+    pub logs_per_transaction: usize,
+    /// The address every log is emitted from
+    pub log_address: String,
+    /// The first topic of every log, eg an event signature hash
+    pub log_topic: String,
+}
+
+impl Default for BlockParams {
+    fn default() -> Self {
+        Self {
+            number: 12369621,
+            transactions: 2,
+            logs_per_transaction: 1,
+            // WETH
+            log_address: "c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2".to_string(),
+            // Transfer(address,address,uint256)
+            log_topic: "ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"
+                .to_string(),
+        }
+    }
+}
+
+fn hash(seed: u64) -> String {
+    format!("{:064x}", seed)
+}
+
+fn address(seed: u64) -> String {
+    format!("{:040x}", seed)
+}
+
+/// Builds a block from the parameters
+pub fn generate_block(params: &BlockParams) -> Value {
+    let number = params.number;
+
+    let transactions = (0..params.transactions)
+        .map(|tx_index| {
+            let tx_seed = number * 1000 + tx_index as u64;
+
+            let logs = (0..params.logs_per_transaction)
+                .map(|log_index| {
+                    json!({
+                        "address": params.log_address,
+                        "topics": [
+                            params.log_topic,
+                            hash(tx_seed + 1),
+                            hash(tx_seed + 2),
+                        ],
+                        "data": hash(log_index as u64 + 1),
+                        "index": log_index,
+                        "blockIndex": tx_index * params.logs_per_transaction + log_index,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            json!({
+                "hash": hash(tx_seed),
+                "from": address(tx_seed + 1),
+                "to": params.log_address,
+                "index": tx_index,
+                "status": "SUCCEEDED",
+                "receipt": {
+                    "logs": logs,
+                },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "number": number.to_string(),
+        "hash": hash(number),
+        "header": {
+            "number": number.to_string(),
+            "parentHash": hash(number.saturating_sub(1)),
+            "timestamp": "2021-05-05T00:00:00Z",
+        },
+        "transactionTraces": transactions,
+    })
+}
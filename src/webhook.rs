@@ -0,0 +1,164 @@
+//! Posts streamed outputs to an http endpoint, for piping output into a local service during development
+//!
+//! Posts run in the background on the stream thread's runtime. When every post is in flight the stream waits,
+//! so a slow endpoint slows the stream down instead of queueing outputs without bound.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::tasks::GuiMessage;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebhookConfig {
+    /// Where outputs are posted, the sink is disabled when this is empty
+    pub url: String,
+    /// How many posts can be in flight at once
+    pub concurrency: usize,
+    /// How many times a failed post is retried before giving up
+    pub retries: u32,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            concurrency: 4,
+            retries: 3,
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.url.trim().is_empty()
+    }
+}
+
+pub struct WebhookSink {
+    client: reqwest::Client,
+    url: String,
+    retries: u32,
+    concurrency: usize,
+    permits: Arc<Semaphore>,
+    delivered: Arc<AtomicUsize>,
+    failed: Arc<AtomicUsize>,
+    gui_sender: Sender<GuiMessage>,
+}
+
+impl WebhookSink {
+    pub fn new(config: &WebhookConfig, gui_sender: Sender<GuiMessage>) -> Self {
+        let concurrency = config.concurrency.max(1);
+        Self {
+            client: reqwest::Client::new(),
+            url: config.url.trim().to_string(),
+            retries: config.retries,
+            concurrency,
+            permits: Arc::new(Semaphore::new(concurrency)),
+            delivered: Arc::new(AtomicUsize::new(0)),
+            failed: Arc::new(AtomicUsize::new(0)),
+            gui_sender,
+        }
+    }
+
+    /// Posts the json in the background, waiting first if too many posts are in flight
+    pub async fn send(&self, body: String) {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Webhook semaphore closed");
+
+        let client = self.client.clone();
+        let url = self.url.clone();
+        let retries = self.retries;
+        let delivered = self.delivered.clone();
+        let failed = self.failed.clone();
+        let gui_sender = self.gui_sender.clone();
+
+        tokio::spawn(async move {
+            match post(&client, &url, body, retries).await {
+                Ok(()) => {
+                    delivered.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(err) => {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    let message = format!("Webhook error: {}", err);
+                    // The gui may have closed mid stream, there's nowhere left to report to
+                    let _ = gui_sender.send(GuiMessage::PushError(message));
+                }
+            }
+            drop(permit);
+        });
+    }
+
+    /// Waits for the posts in flight, and returns how many were delivered and how many failed
+    pub async fn finish(self) -> WebhookTotals {
+        let _all = self
+            .permits
+            .acquire_many(self.concurrency as u32)
+            .await
+            .expect("Webhook semaphore closed");
+
+        WebhookTotals {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How many posts a finished `WebhookSink` delivered, and how many failed after retrying
+pub struct WebhookTotals {
+    pub delivered: usize,
+    pub failed: usize,
+}
+
+impl fmt::Display for WebhookTotals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Webhook: {} delivered, {} failed",
+            self.delivered, self.failed
+        )
+    }
+}
+
+/// Posts the body, retrying with a backoff when the request fails or the endpoint doesn't return a success status
+async fn post(
+    client: &reqwest::Client,
+    url: &str,
+    body: String,
+    retries: u32,
+) -> Result<(), String> {
+    let mut attempt = 0;
+    loop {
+        let result = client
+            .post(url)
+            .header("content-type", "application/json")
+            .body(body.clone())
+            .send()
+            .await;
+
+        let err = match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) => format!("{} responded with {}", url, response.status()),
+            Err(err) => err.to_string(),
+        };
+
+        if attempt >= retries {
+            return Err(err);
+        }
+        attempt += 1;
+        tokio::time::sleep(Duration::from_millis(250 * 2u64.pow(attempt))).await;
+    }
+}
@@ -0,0 +1,62 @@
+//! Keyboard focus cycling between the major panels
+//!
+//! Each frame the visible panels register the widget that should get focus when the panel is focused,
+//! then `cycle` moves focus to the next panel that registered one.
+
+use eframe::egui::{Context, Id};
+
+/// The panels focus cycles through, in order
+#[derive(Default, Clone, Copy, PartialEq)]
+pub enum PanelFocus {
+    #[default]
+    Modules,
+    Editor,
+    Messages,
+}
+
+impl PanelFocus {
+    const ALL: [PanelFocus; 3] = [
+        PanelFocus::Modules,
+        PanelFocus::Editor,
+        PanelFocus::Messages,
+    ];
+
+    fn next(self) -> Self {
+        match self {
+            PanelFocus::Modules => PanelFocus::Editor,
+            PanelFocus::Editor => PanelFocus::Messages,
+            PanelFocus::Messages => PanelFocus::Modules,
+        }
+    }
+
+    fn key(self) -> Id {
+        Id::new(("panel_focus", self as u8))
+    }
+}
+
+/// Forgets last frame's widgets, so hidden panels are skipped
+pub fn clear(ctx: &Context) {
+    ctx.data_mut(|data| {
+        for panel in PanelFocus::ALL {
+            data.remove::<Id>(panel.key());
+        }
+    });
+}
+
+/// Registers the widget that receives focus when the panel is focused
+pub fn register(ctx: &Context, panel: PanelFocus, widget: Id) {
+    ctx.data_mut(|data| data.insert_temp(panel.key(), widget));
+}
+
+/// Moves focus to the next panel that has a registered widget
+pub fn cycle(ctx: &Context, current: &mut PanelFocus) {
+    let mut panel = *current;
+    for _ in 0..PanelFocus::ALL.len() {
+        panel = panel.next();
+        if let Some(widget) = ctx.data(|data| data.get_temp::<Id>(panel.key())) {
+            ctx.memory_mut(|memory| memory.request_focus(widget));
+            *current = panel;
+            return;
+        }
+    }
+}
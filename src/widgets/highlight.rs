@@ -0,0 +1,141 @@
+//! Syntax highlighting for rhai in the module code editor
+//!
+//! A small tokenizer rather than a full parser, it only needs to tell keywords, numbers, strings and comments apart.
+
+use eframe::egui::{
+    text::{LayoutJob, TextFormat},
+    Color32, Style, TextStyle,
+};
+
+/// Highlighted as keywords, the words that shape rhai code rather than name things
+const KEYWORDS: &[&str] = &[
+    "fn", "let", "const", "if", "else", "switch", "while", "loop", "for", "in", "do", "until",
+    "break", "continue", "return", "throw", "try", "catch", "true", "false", "import", "export",
+    "as", "private", "this", "global", "Fn",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token {
+    Plain,
+    Keyword,
+    Number,
+    String,
+    Comment,
+}
+
+impl Token {
+    fn color(self, dark_mode: bool) -> Color32 {
+        match (self, dark_mode) {
+            (Token::Plain, true) => Color32::from_gray(210),
+            (Token::Plain, false) => Color32::from_gray(40),
+            (Token::Keyword, true) => Color32::from_rgb(255, 120, 170),
+            (Token::Keyword, false) => Color32::from_rgb(200, 40, 110),
+            (Token::Number, true) => Color32::from_rgb(160, 200, 255),
+            (Token::Number, false) => Color32::from_rgb(20, 90, 190),
+            (Token::String, true) => Color32::from_rgb(170, 220, 120),
+            (Token::String, false) => Color32::from_rgb(40, 130, 30),
+            (Token::Comment, true) => Color32::from_gray(130),
+            (Token::Comment, false) => Color32::from_gray(120),
+        }
+    }
+}
+
+/// Lays out rhai code with each token colored by its kind
+pub fn highlight(style: &Style, code: &str) -> LayoutJob {
+    let font_id = TextStyle::Monospace.resolve(style);
+    let dark_mode = style.visuals.dark_mode;
+
+    let mut job = LayoutJob::default();
+    for (token, text) in tokenize(code) {
+        let format = TextFormat::simple(font_id.clone(), token.color(dark_mode));
+        job.append(text, 0.0, format);
+    }
+    job
+}
+
+/// Splits the code into tokens, which together cover all of it so nothing is lost from the layout
+fn tokenize(code: &str) -> Vec<(Token, &str)> {
+    let mut tokens: Vec<(Token, &str)> = Vec::new();
+    let mut rest = code;
+
+    while let Some(c) = rest.chars().next() {
+        let (token, len) = if rest.starts_with("//") {
+            (Token::Comment, rest.find('\n').unwrap_or(rest.len()))
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            let len = body.find("*/").map_or(rest.len(), |end| end + 4);
+            (Token::Comment, len)
+        } else if c == '"' || c == '`' || c == '\'' {
+            (Token::String, quoted_len(rest, c))
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            (Token::Number, len)
+        } else if c.is_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            let token = if KEYWORDS.contains(&&rest[..len]) {
+                Token::Keyword
+            } else {
+                Token::Plain
+            };
+            (token, len)
+        } else {
+            (Token::Plain, c.len_utf8())
+        };
+
+        // Runs of plain characters are merged, so the layout doesn't get a section per character
+        let start = code.len() - rest.len();
+        match tokens.last_mut() {
+            Some((Token::Plain, text)) if token == Token::Plain => {
+                *text = &code[start - text.len()..start + len];
+            }
+            _ => tokens.push((token, &rest[..len])),
+        }
+        rest = &rest[len..];
+    }
+
+    tokens
+}
+
+/// The length of the quoted string at the start of `text`, up to the end of the text if it isn't closed
+fn quoted_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == quote => return i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_cover_all_of_the_code() {
+        let code = "fn f(x) { /* a\n comment */ x + 1 } // done";
+        let joined = tokenize(code)
+            .iter()
+            .map(|(_, text)| *text)
+            .collect::<String>();
+        assert_eq!(joined, code);
+    }
+
+    #[test]
+    fn block_comments_run_to_their_end_or_the_code_end() {
+        let tokens = tokenize("/* a */x");
+        assert!(matches!(tokens[0], (Token::Comment, "/* a */")));
+
+        let tokens = tokenize("x /* unclosed");
+        assert!(matches!(
+            tokens.last(),
+            Some((Token::Comment, "/* unclosed"))
+        ));
+    }
+}
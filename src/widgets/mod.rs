@@ -1,2 +1,4 @@
+pub mod focus;
+pub mod highlight;
 pub mod module_panel;
 pub mod panels;
@@ -1,191 +1,828 @@
 use std::{
-    collections::HashMap,
-    sync::{mpsc, Arc, RwLock},
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, RwLock},
 };
 
-use eframe::egui::{self, ComboBox, Key, Response, Ui, Widget, Window};
+use eframe::egui::{self, ComboBox, Key, ScrollArea, Ui, Window};
 use rand::random;
+use serde_json::Value;
 
-use crate::{Module, WorkerMessage};
+use super::{
+    focus::{self, PanelFocus},
+    highlight,
+};
+use crate::{
+    block_cache::BlockCache,
+    modules::{self, now, ModuleSort, TemplateKind},
+    tasks::{Channels, DepStep},
+    GuiMessage, Module, WorkerMessage,
+};
+
+/// The "Clone with new inputs" dialog, kept in egui memory between frames
+#[derive(Clone)]
+struct CloneWizard {
+    source: i64,
+    name: String,
+    inputs: Vec<String>,
+}
+
+impl CloneWizard {
+    fn id() -> egui::Id {
+        egui::Id::new("clone_wizard")
+    }
+
+    /// Checks the clone can be created, returning the problems if not
+    fn validate(&self, modules: &HashMap<i64, Module>) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Err(err) = Module::validate_name(&self.name) {
+            errors.push(err);
+        } else if modules.values().any(|module| module.name() == self.name) {
+            errors.push(format!("A module named `{}` already exists", self.name));
+        }
+
+        for input in self.inputs.iter() {
+            let reserved = input == "BLOCK" || input == "PARAMS";
+            if !reserved && !modules.values().any(|module| module.name() == input) {
+                errors.push(format!("Unknown input: {}", input));
+            }
+        }
+
+        errors
+    }
+}
+
+/// A name field for the module or its parameters, returning the new name once editing ends with a valid one
+///
+/// The name being typed is kept as a draft in egui memory under `draft_id`, and only returned when the
+/// field loses focus or Enter is pressed, so a rename isn't applied on every keystroke.
+/// An invalid name stays a draft shown with the error, so it never reaches codegen.
+fn name_field(ui: &mut Ui, draft_id: egui::Id, name: &str) -> Option<String> {
+    let mut draft = ui
+        .data(|data| data.get_temp::<String>(draft_id))
+        .unwrap_or_else(|| name.to_string());
+
+    let response = ui.text_edit_singleline(&mut draft);
+    let valid = Module::validate_name(&draft);
+
+    if let Err(err) = &valid {
+        ui.colored_label(egui::Color32::RED, err);
+    }
+
+    if valid.is_ok() && !response.has_focus() {
+        ui.data_mut(|data| data.remove::<String>(draft_id));
+        return (response.lost_focus() && draft != name).then_some(draft);
+    }
+    ui.data_mut(|data| data.insert_temp(draft_id, draft));
+    None
+}
+
+/// Whether an input is a map, a store, the block source or the params, or `None` if nothing has that name
+fn input_kind(input: &str, module_kinds: &[(String, &'static str)]) -> Option<&'static str> {
+    match input {
+        "BLOCK" => return Some("source"),
+        "PARAMS" => return Some("params"),
+        _ => {}
+    }
+    module_kinds
+        .iter()
+        .find(|(name, _)| name == input)
+        .map(|(_, kind)| *kind)
+}
+
+/// The input's name with its kind, eg `foo (map)`
+fn input_label(input: &str, module_kinds: &[(String, &'static str)]) -> String {
+    format!(
+        "{} ({})",
+        input,
+        input_kind(input, module_kinds).unwrap_or("unknown")
+    )
+}
+
+/// Edits a module's inputs, which can be added, removed and reordered since they're the handler's arguments
+fn inputs_editor(
+    ui: &mut Ui,
+    id: i64,
+    name: &str,
+    inputs: &mut Vec<String>,
+    module_kinds: &[(String, &'static str)],
+) {
+    let mut move_up = None;
+    let mut move_down = None;
+    let count = inputs.len();
+
+    for (i, input) in inputs.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ComboBox::from_id_source(("module_input", id, i))
+                .selected_text(input_label(input, module_kinds))
+                .show_ui(ui, |ui| {
+                    // A module can't take itself as an input
+                    for (module_name, kind) in
+                        module_kinds.iter().filter(|(other, _)| other != name)
+                    {
+                        let label = format!("{} ({})", module_name, kind);
+                        ui.selectable_value(input, module_name.to_string(), label);
+                    }
+                    ui.selectable_value(input, "BLOCK".to_string(), "BLOCK (source)");
+                    ui.selectable_value(input, "PARAMS".to_string(), "PARAMS (params)");
+                });
+
+            if ui
+                .add_enabled(i > 0, egui::Button::new("⬆").small())
+                .clicked()
+            {
+                move_up = Some(i);
+            }
+            if ui
+                .add_enabled(i + 1 < count, egui::Button::new("⬇").small())
+                .clicked()
+            {
+                move_down = Some(i);
+            }
+        });
+
+        if input_kind(input, module_kinds).is_none() {
+            ui.colored_label(
+                egui::Color32::YELLOW,
+                format!("⚠ `{}` isn't a module, so the build will fail", input),
+            );
+        }
+    }
+
+    if let Some(i) = move_up {
+        inputs.swap(i - 1, i);
+    }
+    if let Some(i) = move_down {
+        inputs.swap(i, i + 1);
+    }
+
+    ui.horizontal(|ui| {
+        if ui.small_button("+ input").clicked() {
+            inputs.push("BLOCK".to_string());
+        }
+        if ui
+            .add_enabled(!inputs.is_empty(), egui::Button::new("- input").small())
+            .clicked()
+        {
+            inputs.pop();
+        }
+    });
+}
+
+/// What the module panel or menu bar asked for, applied once the frame is done with the editor state
+pub enum ModuleAction {
+    /// Adds the module, with a numeric suffix on its name if a module already has it
+    Add(Module),
+    /// Renames the module with the first name to the second
+    Rename(String, String),
+    /// Builds the modules, once they're checked for cycles
+    Build,
+}
 
 pub struct ModulePanel<'a> {
     context: &'a egui::Context,
-    channel: mpsc::Sender<WorkerMessage>,
+    channels: Channels<'a>,
     modules: &'a mut HashMap<i64, Module>,
+    results: &'a HashMap<i64, VecDeque<Value>>,
+    stored_outputs: &'a BTreeMap<String, Value>,
+    block_cache: &'a BlockCache,
+    sort: &'a mut ModuleSort,
 }
 
 impl<'a> ModulePanel<'a> {
     pub fn new(
         context: &'a egui::Context,
-        channel: mpsc::Sender<WorkerMessage>,
+        channels: Channels<'a>,
         modules: &'a mut HashMap<i64, Module>,
+        results: &'a HashMap<i64, VecDeque<Value>>,
+        stored_outputs: &'a BTreeMap<String, Value>,
+        block_cache: &'a BlockCache,
+        sort: &'a mut ModuleSort,
     ) -> Self {
         Self {
             context,
             modules,
-            channel,
+            channels,
+            results,
+            stored_outputs,
+            block_cache,
+            sort,
         }
     }
 }
 
-impl Widget for ModulePanel<'_> {
-    fn ui(self, ui: &mut Ui) -> Response {
+impl ModulePanel<'_> {
+    /// Shows the panel, returning the changes it asked for that go through the editor state
+    pub fn show(self, ui: &mut Ui) -> Vec<ModuleAction> {
         let ctx = self.context;
         let modules = self.modules;
+        let results = self.results;
+        let stored_outputs = self.stored_outputs;
+        let block_cache = self.block_cache;
+        let sort = self.sort;
+
+        let mut actions = Vec::new();
+
+        // Shown first so the panel keeps room for the buttons however long the list gets
+        egui::TopBottomPanel::bottom("module_panel_add").show_inside(ui, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Add Mfn").clicked() {
+                    actions.push(ModuleAction::Add(Module::Map {
+                        name: "template_mfn".to_string(),
+                        code: "fn template_mfn(BLOCK) { block.number }".to_string(),
+                        inputs: vec!["BLOCK".to_string()],
+                        editing: true,
+                        last_modified: now(),
+                        handler: None,
+                        params: String::new(),
+                    }));
+                }
+                if ui.button("Add SFN").clicked() {
+                    actions.push(ModuleAction::Add(Module::Store {
+                        name: "template_sfn".to_string(),
+                        code: "fn template_sfn(test_map,s) { s.set(test_map); }".to_string(),
+                        inputs: vec!["test_map".to_string()],
+                        update_policy: "set".to_string(),
+                        editing: true,
+                        last_modified: now(),
+                        store_param: modules::default_store_param(),
+                        handler: None,
+                        params: String::new(),
+                    }));
+                }
+                ui.menu_button("New from template", |ui| {
+                    for kind in TemplateKind::ALL {
+                        if ui.button(kind.label()).clicked() {
+                            actions.push(ModuleAction::Add(Module::template(kind)));
+                            ui.close_menu();
+                        }
+                    }
+                });
+            })
+        });
 
         ui.heading("Modules");
 
+        ComboBox::from_label("Sort by")
+            .selected_text(sort.label())
+            .show_ui(ui, |ui| {
+                for option in [ModuleSort::Name, ModuleSort::LastModified, ModuleSort::Kind] {
+                    ui.selectable_value(sort, option, option.label());
+                }
+            });
+
+        // Which windows were open before the last Open All or Close All, so Restore can bring them back
+        let restore_id = egui::Id::new("module_windows_restore");
+        let restore = ui.data(|data| data.get_temp::<Vec<i64>>(restore_id));
+        ui.horizontal(|ui| {
+            let open_all = ui.small_button("Open All").clicked();
+            let close_all = ui.small_button("Close All").clicked();
+            if open_all || close_all {
+                let open_ids = modules
+                    .iter()
+                    .filter(|(_, module)| *module.editing())
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>();
+                ui.data_mut(|data| data.insert_temp(restore_id, open_ids));
+                for module in modules.values_mut() {
+                    *module.editing_mut() = open_all;
+                }
+            }
+
+            let restore_clicked = ui
+                .add_enabled(restore.is_some(), egui::Button::new("Restore").small())
+                .on_hover_text("Reopen the windows that were open before Open All or Close All")
+                .clicked();
+            if let Some(open_ids) = restore.filter(|_| restore_clicked) {
+                for (id, module) in modules.iter_mut() {
+                    *module.editing_mut() = open_ids.contains(id);
+                }
+                ui.data_mut(|data| data.remove::<Vec<i64>>(restore_id));
+            }
+        });
+
         ui.separator();
 
-        let module_names = &modules
+        let module_kinds = &modules
+            .values()
+            .map(|module| (module.name().to_string(), module.kind()))
+            .collect::<Vec<_>>();
+
+        let mut ids = modules.keys().copied().collect::<Vec<_>>();
+        match sort {
+            ModuleSort::Name => ids.sort_by_key(|id| modules[id].name().to_string()),
+            // Most recently modified first
+            ModuleSort::LastModified => {
+                ids.sort_by_key(|id| std::cmp::Reverse(modules[id].last_modified()))
+            }
+            ModuleSort::Kind => {
+                ids.sort_by_key(|id| (modules[id].kind(), modules[id].name().to_string()))
+            }
+        }
+
+        // Modules can't be removed while iterating, so these are applied after the loop
+        let mut to_delete = None;
+        let mut rename = None;
+        let mut to_clone = None;
+        let mut run_with_deps = None;
+        let mut build = false;
+
+        let search_id = egui::Id::new("module_search");
+        let mut search = ui
+            .data(|data| data.get_temp::<String>(search_id))
+            .unwrap_or_default();
+        ui.add(egui::TextEdit::singleline(&mut search).hint_text("Search modules"));
+        let term = search.trim().to_lowercase();
+        ui.data_mut(|data| data.insert_temp(search_id, search));
+
+        // Only the list is filtered, open module windows stay open while searching
+        let shown_ids = ids
             .iter()
-            .map(|(k, v)| v.name().to_string())
+            .copied()
+            .filter(|id| term.is_empty() || modules[id].name().to_lowercase().contains(&term))
             .collect::<Vec<_>>();
+        ui.weak(format!(
+            "Showing {} of {} modules",
+            shown_ids.len(),
+            ids.len()
+        ));
 
-        for (id, module) in modules.iter_mut() {
-            let module_name = module.name().to_string();
+        ScrollArea::vertical()
+            .auto_shrink([false, false])
+            .show(ui, |ui| {
+                for (i, id) in shown_ids.iter().enumerate() {
+                    let module = modules.get_mut(id).unwrap();
+                    let module_name = module.name().to_string();
 
-            ui.checkbox(module.editing_mut(), module_name);
+                    ui.horizontal(|ui| {
+                        let checkbox = ui.checkbox(module.editing_mut(), module_name);
+                        if i == 0 {
+                            focus::register(ctx, PanelFocus::Modules, checkbox.id);
+                        }
+                        if ui
+                            .small_button("🗑")
+                            .on_hover_text("Delete module")
+                            .clicked()
+                        {
+                            to_delete = Some(*id);
+                        }
+                    });
+                    ui.end_row();
+                }
+            });
+
+        for id in ids.iter() {
+            let module = modules.get_mut(id).unwrap();
 
             if *module.editing() {
+                let before = module.clone();
+
+                // Keyed by id so renaming doesn't reset the window or drop focus from the name field
+                // Shortcuts only apply to the module being edited, not every open module window
+                let editor_id = egui::Id::new(("module_editor", *id));
+                let editor_focused = ctx.memory(|memory| memory.has_focus(editor_id));
+                if editor_focused && ctx.input(|i| i.key_pressed(Key::B) && i.modifiers.ctrl) {
+                    build = true;
+                }
+
                 Window::new(module.name())
+                    .id(egui::Id::new(("module_window", *id)))
                     .max_height(800.0)
                     .max_width(600.0)
                     .show(ctx, |ui| {
                         ui.vertical(|ui| {
                             ui.horizontal(|ui| {
-                                if ui.button("Eval").clicked()
-                                    || ctx.input(|i| i.key_pressed(Key::Enter) && i.modifiers.ctrl)
+                                let eval_shortcut = editor_focused
+                                    && ctx.input(|i| i.key_pressed(Key::Enter) && i.modifiers.ctrl);
+                                if ui.button("Eval").on_hover_text("Ctrl+Enter").clicked()
+                                    || eval_shortcut
                                 {
                                     let code = module.code();
                                     let message = WorkerMessage::Eval(code.to_string());
-                                    self.channel.send(message).unwrap();
+                                    self.channels.worker.send(message).unwrap();
                                 }
 
-                                ui.collapsing("Module Configuration", |ui| match module {
-                                    Module::Map {
-                                        name,
-                                        code,
-                                        inputs,
-                                        editing,
-                                    } => {
-                                        ui.label("Module Name");
-                                        ui.text_edit_singleline(name);
-                                        ui.separator();
+                                let slot_id = egui::Id::new(("module_block_slot", *id));
+                                let mut slot = ui
+                                    .data(|data| data.get_temp::<usize>(slot_id))
+                                    .unwrap_or_default()
+                                    .min(block_cache.len().saturating_sub(1));
+                                let slot_label = |slot: usize| match block_cache.block_number(slot) {
+                                    Some(number) => format!("Slot {} (block {})", slot, number),
+                                    None if block_cache.get(slot).is_null() => {
+                                        format!("Slot {} (empty)", slot)
+                                    }
+                                    None => format!("Slot {} (not a block)", slot),
+                                };
 
-                                        ui.label("Inputs. (Each on a new line)");
-                                        for input in inputs.iter_mut() {
-                                            ComboBox::from_label("Input")
-                                                .selected_text(input.as_str())
-                                                .show_ui(ui, |ui| {
-                                                    for module_name in module_names.iter() {
-                                                        if &module_name == &input {
-                                                            continue;
-                                                        }
-                                                        ui.selectable_value(
-                                                            input,
-                                                            module_name.to_string(),
-                                                            module_name,
-                                                        );
-                                                    }
-                                                    ui.selectable_value(
-                                                        input,
-                                                        "BLOCK".to_string(),
-                                                        "BLOCK",
-                                                    );
-                                                });
+                                ComboBox::from_id_source(slot_id)
+                                    .selected_text(slot_label(slot))
+                                    .show_ui(ui, |ui| {
+                                        for i in 0..block_cache.len() {
+                                            ui.selectable_value(&mut slot, i, slot_label(i));
                                         }
-                                    }
-                                    Module::Store {
-                                        name,
-                                        code,
-                                        inputs,
-                                        update_policy,
-                                        editing,
-                                    } => {
-                                        ui.label("Store Configuration");
-                                        ui.separator();
+                                    });
+                                ui.data_mut(|data| data.insert_temp(slot_id, slot));
 
-                                        ui.label("Module Name");
-                                        ui.text_edit_singleline(name);
-                                        ui.separator();
+                                // Stores are called with the block and a local store to write to,
+                                // so one with several inputs can't be run this way
+                                let single_input = matches!(module, Module::Map { .. })
+                                    || module.inputs().len() <= 1;
+                                if ui
+                                    .add_enabled(
+                                        single_input && !block_cache.get(slot).is_null(),
+                                        egui::Button::new("Run on block"),
+                                    )
+                                    .on_hover_text("Call the handler with the cached block")
+                                    .clicked()
+                                {
+                                    let block = block_cache.get(slot).clone();
+                                    let message = WorkerMessage::EvalWithArgs(
+                                        module.name().to_string(),
+                                        module.handler().to_string(),
+                                        vec![block],
+                                        matches!(module, Module::Store { .. }),
+                                    );
+                                    self.channels.worker.send(message).unwrap();
+                                }
 
-                                        ui.label("Update Policy");
-                                        ComboBox::from_label("Update Policy")
-                                            .selected_text(update_policy.as_str())
-                                            .show_ui(ui, |ui| {
-                                                ui.selectable_value(
-                                                    update_policy,
-                                                    "set".to_string(),
-                                                    "set",
-                                                );
+                                if ui
+                                    .add_enabled(
+                                        !block_cache.get(slot).is_null(),
+                                        egui::Button::new("Run with deps"),
+                                    )
+                                    .on_hover_text(
+                                        "Run the modules this one depends on with the cached block first, feeding their outputs in",
+                                    )
+                                    .clicked()
+                                {
+                                    run_with_deps = Some((*id, block_cache.get(slot).clone()));
+                                }
+
+                                if let Module::Map { .. } = &*module {
+                                    // Remembered across restarts, like the stored outputs themselves
+                                    let output_id = egui::Id::new(("module_test_output", *id));
+                                    let mut output = ui
+                                        .data_mut(|data| data.get_persisted::<String>(output_id))
+                                        .filter(|output| stored_outputs.contains_key(output));
+
+                                    ComboBox::from_id_source(output_id)
+                                        .selected_text(output.as_deref().unwrap_or("No stored output"))
+                                        .show_ui(ui, |ui| {
+                                            for output_name in stored_outputs.keys() {
                                                 ui.selectable_value(
-                                                    update_policy,
-                                                    "setOnce".to_string(),
-                                                    "setOnce",
+                                                    &mut output,
+                                                    Some(output_name.clone()),
+                                                    output_name,
                                                 );
-                                            });
+                                            }
+                                        });
 
-                                        ui.label("Inputs. (Each on a new line)");
-                                        for input in inputs.iter_mut() {
-                                            ComboBox::from_label("Input")
-                                                .selected_text(input.as_str())
+                                    if ui
+                                        .add_enabled(output.is_some(), egui::Button::new("Eval Output"))
+                                        .on_hover_text("Call the handler with the stored output in place of its input")
+                                        .clicked()
+                                    {
+                                        if let Some(output) = &output {
+                                            let message = WorkerMessage::EvalWithOutput(
+                                                module.name().to_string(),
+                                                module.handler().to_string(),
+                                                output.clone(),
+                                            );
+                                            self.channels.worker.send(message).unwrap();
+                                        }
+                                    }
+
+                                    if let Some(output) = output {
+                                        ui.data_mut(|data| data.insert_persisted(output_id, output));
+                                    }
+                                }
+
+                                if ui
+                                    .button("Clone…")
+                                    .on_hover_text("Clone this module with different inputs")
+                                    .clicked()
+                                {
+                                    to_clone = Some(*id);
+                                }
+
+                                ui.collapsing("Module Configuration", |ui| {
+                                    let mut kind = module.kind();
+                                    ComboBox::from_label("Kind").selected_text(kind).show_ui(
+                                        ui,
+                                        |ui| {
+                                            ui.selectable_value(&mut kind, "map", "map");
+                                            ui.selectable_value(&mut kind, "store", "store");
+                                        },
+                                    );
+                                    if kind != module.kind() {
+                                        let current = module.clone();
+                                        *module = match kind {
+                                            "store" => current.convert_to_store("set".to_string()),
+                                            _ => current.convert_to_map(),
+                                        };
+                                    }
+                                    ui.separator();
+
+                                    let name_draft_id = egui::Id::new(("module_name_draft", *id));
+                                    match module {
+                                        Module::Map {
+                                            name,
+                                            code,
+                                            inputs,
+                                            editing,
+                                            ..
+                                        } => {
+                                            ui.label("Module Name");
+                                            if let Some(new_name) = name_field(ui, name_draft_id, name) {
+                                                rename = Some((name.clone(), new_name));
+                                            }
+                                            ui.separator();
+
+                                            ui.label("Inputs, in handler argument order");
+                                            inputs_editor(ui, *id, name, inputs, module_kinds);
+                                        }
+                                        Module::Store {
+                                            name,
+                                            code,
+                                            inputs,
+                                            update_policy,
+                                            editing,
+                                            store_param,
+                                            ..
+                                        } => {
+                                            ui.label("Store Configuration");
+                                            ui.separator();
+
+                                            ui.label("Module Name");
+                                            if let Some(new_name) = name_field(ui, name_draft_id, name) {
+                                                rename = Some((name.clone(), new_name));
+                                            }
+                                            ui.separator();
+
+                                            ui.label("Update Policy");
+                                            ComboBox::from_label("Update Policy")
+                                                .selected_text(update_policy.as_str())
                                                 .show_ui(ui, |ui| {
-                                                    for module_name in module_names.iter() {
+                                                    for policy in Module::valid_update_policies() {
                                                         ui.selectable_value(
-                                                            input,
-                                                            module_name.to_string(),
-                                                            module_name,
+                                                            update_policy,
+                                                            policy.to_string(),
+                                                            *policy,
                                                         );
                                                     }
-                                                    ui.selectable_value(
-                                                        input,
-                                                        "BLOCK".to_string(),
-                                                        "BLOCK",
-                                                    );
                                                 });
+
+                                            // The store itself is always passed after these, so it isn't listed
+                                            ui.label("Inputs, in handler argument order");
+                                            inputs_editor(ui, *id, name, inputs, module_kinds);
+                                            ui.separator();
+
+                                            ui.label("Store Parameter")
+                                                .on_hover_text("The handler's last parameter, which the store is passed as");
+                                            let draft_id = egui::Id::new(("store_param_draft", *id));
+                                            if let Some(new_param) = name_field(ui, draft_id, store_param) {
+                                                *store_param = new_param;
+                                            }
                                         }
                                     }
+                                    ui.separator();
+
+                                    ui.label("Handler")
+                                        .on_hover_text("The rhai function the module runs, named after the module unless it's changed here");
+                                    let draft_id = egui::Id::new(("handler_draft", *id));
+                                    let handler = module.handler().to_string();
+                                    if let Some(new_handler) = name_field(ui, draft_id, &handler) {
+                                        // Set back to the name, it follows the module through renames again
+                                        let follows_name = new_handler == module.name();
+                                        *module.handler_mut() = (!follows_name).then_some(new_handler);
+                                    }
+
+                                    if module.inputs().iter().any(|input| input == "PARAMS") {
+                                        ui.separator();
+                                        ui.label("Default Params")
+                                            .on_hover_text("What the PARAMS input is when a run doesn't set it");
+                                        ui.text_edit_singleline(module.params_mut());
+                                    }
                                 });
                             });
-                            ui.add_sized(
+
+                            if let Some(results) = results.get(id) {
+                                ui.collapsing(format!("Results ({})", results.len()), |ui| {
+                                    // Newest result first
+                                    for (i, result) in results.iter().rev().enumerate() {
+                                        ui.collapsing(format!("Result {}", i + 1), |ui| {
+                                            let id = format!("module_result:{}:{}", id, i);
+                                            egui_json_tree::JsonTree::new(id, result).show(ui);
+                                        });
+                                    }
+                                });
+                            }
+
+                            if !module.signature_in_sync() {
+                                ui.horizontal_wrapped(|ui| {
+                                    let warning = match module.signature() {
+                                        Some(_) => format!(
+                                            "The handler's parameters don't match the inputs, expected `{}`",
+                                            module.expected_signature()
+                                        ),
+                                        None => format!(
+                                            "No `fn {}(...)` handler found in the code",
+                                            module.handler()
+                                        ),
+                                    };
+                                    ui.colored_label(egui::Color32::YELLOW, warning);
+                                    if module.signature().is_some()
+                                        && ui.small_button("Sync signature").clicked()
+                                    {
+                                        module.sync_signature();
+                                    }
+                                });
+                            }
+
+                            for warning in module.lint() {
+                                ui.colored_label(egui::Color32::YELLOW, warning);
+                            }
+
+                            let mut layouter = |ui: &Ui, code: &str, wrap_width: f32| {
+                                let mut job = highlight::highlight(ui.style(), code);
+                                job.wrap.max_width = wrap_width;
+                                ui.fonts(|fonts| fonts.layout_job(job))
+                            };
+                            let editor = ui.add_sized(
                                 ui.available_size(),
-                                egui::TextEdit::multiline(module.code_mut()).code_editor(),
-                            ); // Assuming .code_editor() is a method or extension you have for making a TextEdit suitable for code
-                               //ui.code_editor(module.code_mut());
+                                egui::TextEdit::multiline(module.code_mut())
+                                    .id(editor_id)
+                                    .code_editor()
+                                    .layouter(&mut layouter),
+                            );
+                            focus::register(ctx, PanelFocus::Editor, editor.id);
                         });
                     });
+
+                if *module != before {
+                    module.touch();
+                }
             }
-            ui.end_row();
         }
 
-        ui.horizontal(|ui| {
-            if ui.button("Add Mfn").clicked() {
-                let name = "template_mfn";
-                modules.insert(
-                    random(),
-                    Module::Map {
-                        name: name.to_string(),
-                        code: format!("fn {name}(BLOCK) {{ block.number }}"),
-                        inputs: vec!["BLOCK".to_string()],
-                        editing: true,
-                    },
-                );
+        if build {
+            actions.push(ModuleAction::Build);
+        }
+
+        if let Some((id, block)) = run_with_deps {
+            match modules::upstream_order(modules, id) {
+                Ok(order) => {
+                    let mut steps = Vec::with_capacity(order.len());
+                    for id in order {
+                        let module = &modules[&id];
+                        // Evaluated first, so every handler in the chain is the current code
+                        let message = WorkerMessage::Eval(module.code().to_string());
+                        self.channels.worker.send(message).unwrap();
+                        steps.push(DepStep {
+                            name: module.name().to_string(),
+                            handler: module.handler().to_string(),
+                            inputs: module.inputs().clone(),
+                            params: module.params().to_string(),
+                            store: matches!(module, Module::Store { .. }),
+                        });
+                    }
+                    let message = WorkerMessage::EvalWithDeps(steps, block);
+                    self.channels.worker.send(message).unwrap();
+                }
+                Err(cycle) => {
+                    let message = format!(
+                        "Can't run `{}`: its dependencies depend on each other in a cycle: {}",
+                        modules[&id].name(),
+                        cycle.join(" -> ")
+                    );
+                    self.channels
+                        .gui
+                        .send(GuiMessage::PushError(message))
+                        .unwrap();
+                }
             }
-            if ui.button("Add SFN").clicked() {
-                let name = "template_sfn";
-                modules.insert(
-                    random(),
-                    Module::Store {
-                        name: name.to_string(),
-                        code: format!("fn {name}(test_map,s) {{ s.set(test_map); }}"),
-                        inputs: vec!["test_map".to_string()],
-                        update_policy: "set".to_string(),
-                        editing: true,
-                    },
+        }
+
+        if let Some(id) = to_clone {
+            let source = &modules[&id];
+            let wizard = CloneWizard {
+                source: id,
+                name: modules::unique_name(modules, source.name()),
+                inputs: source.inputs().clone(),
+            };
+            ctx.data_mut(|data| data.insert_temp(CloneWizard::id(), wizard));
+        }
+
+        if let Some(mut wizard) = ctx.data(|data| data.get_temp::<CloneWizard>(CloneWizard::id())) {
+            let mut open = true;
+            let mut done = false;
+            let mut cancelled = false;
+
+            match modules.get(&wizard.source) {
+                Some(source) => {
+                    let errors = wizard.validate(modules);
+
+                    Window::new("Clone With New Inputs")
+                        .open(&mut open)
+                        .show(ctx, |ui| {
+                            ui.label(format!("Cloning `{}`", source.name()));
+                            ui.separator();
+
+                            ui.label("New Name");
+                            ui.text_edit_singleline(&mut wizard.name);
+                            ui.separator();
+
+                            ui.label("Inputs");
+                            for (i, (input, original)) in
+                                wizard.inputs.iter_mut().zip(source.inputs()).enumerate()
+                            {
+                                ComboBox::new(("clone_input", i), original)
+                                    .selected_text(input_label(input, module_kinds))
+                                    .show_ui(ui, |ui| {
+                                        for (module_name, kind) in module_kinds.iter() {
+                                            ui.selectable_value(
+                                                input,
+                                                module_name.to_string(),
+                                                format!("{} ({})", module_name, kind),
+                                            );
+                                        }
+                                        ui.selectable_value(
+                                            input,
+                                            "BLOCK".to_string(),
+                                            "BLOCK (source)",
+                                        );
+                                        ui.selectable_value(
+                                            input,
+                                            "PARAMS".to_string(),
+                                            "PARAMS (params)",
+                                        );
+                                    });
+                            }
+
+                            for error in errors.iter() {
+                                ui.colored_label(egui::Color32::RED, error);
+                            }
+
+                            ui.horizontal(|ui| {
+                                if ui
+                                    .add_enabled(errors.is_empty(), egui::Button::new("Create"))
+                                    .clicked()
+                                {
+                                    done = true;
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    cancelled = true;
+                                }
+                            });
+                        });
+
+                    if done {
+                        let clone = source.clone_with_inputs(&wizard.name, wizard.inputs.clone());
+                        modules.insert(random(), clone);
+                    }
+                }
+                // The source module was deleted while the dialog was open
+                None => open = false,
+            }
+
+            ctx.data_mut(|data| {
+                if open && !done && !cancelled {
+                    data.insert_temp(CloneWizard::id(), wizard);
+                } else {
+                    data.remove::<CloneWizard>(CloneWizard::id());
+                }
+            });
+        }
+
+        if let Some((old, new)) = rename {
+            actions.push(ModuleAction::Rename(old, new));
+        }
+
+        if let Some(id) = to_delete {
+            let name = modules[&id].name().to_string();
+            let dependents = modules
+                .values()
+                .filter(|module| module.inputs().contains(&name))
+                .map(|module| module.name())
+                .collect::<Vec<_>>();
+
+            // Codegen can't resolve an input that no longer exists, so keep the module until nothing uses it
+            if dependents.is_empty() {
+                modules.remove(&id);
+            } else {
+                let message = format!(
+                    "Warning: can't delete module `{}`, it is still an input to: {}",
+                    name,
+                    dependents.join(", ")
                 );
+                self.channels
+                    .gui
+                    .send(GuiMessage::PushMessage(message))
+                    .unwrap();
             }
-        })
-        .response
+        }
+
+        actions
     }
 }
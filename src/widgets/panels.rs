@@ -1,38 +1,372 @@
-use std::sync::mpsc::Sender;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::mpsc::Sender,
+    time::{Duration, SystemTime},
+};
 
 use eframe::{
-    egui::{self, menu, Context, ScrollArea, Ui, Window},
+    egui::{self, menu, Align, Color32, Context, Layout, ScrollArea, Ui, Window},
     Frame,
 };
+use rand::random;
+use serde_json::Value;
 
+use super::{
+    focus::{self, PanelFocus},
+    module_panel::ModuleAction,
+};
 use crate::{
-    block_cache::BlockCache,
-    tasks::{GuiMessage, MessageKind, StreamMessages, WorkerMessage},
-    EditorConfig, EditorViews, UserConfig,
+    block_cache::{self, BlockCache},
+    modules::{Module, SourceMap},
+    project::ProjectAction,
+    redact::Redaction,
+    schema_import,
+    tasks::{
+        BuildResult, Channels, GuiMessage, Message, MessageKind, StreamMessages, StreamProgress,
+        WorkerMessage,
+    },
+    CompileStatus, EditorConfig, EditorViews, Theme, UserConfig, MAX_ZOOM, MIN_ZOOM,
 };
 
+/// Why building, generating or running the modules is disabled when there are none
+pub const NO_MODULES: &str = "No modules defined";
+
+/// Generates the source without sending anything to a stream, opening it if it generated
+///
+/// Failures are reported as a failed build, so they look the same as the errors `build` gives.
+pub fn generate_only(
+    modules: &HashMap<i64, Module>,
+    source_file: &str,
+    source_errors: &[String],
+    gui_sender: &Sender<GuiMessage>,
+) -> bool {
+    let log = match crate::modules::dependency_order(modules) {
+        Err(cycle) => format!(
+            "Modules depend on each other in a cycle: {}",
+            cycle.join(" -> ")
+        ),
+        Ok(_) if modules.is_empty() => NO_MODULES.to_string(),
+        Ok(_) if !source_errors.is_empty() => source_errors.join("\n"),
+        Ok(_) => {
+            let message = format!(
+                "Generated {} lines of source for {} modules",
+                source_file.lines().count(),
+                modules.len()
+            );
+            gui_sender.send(GuiMessage::PushMessage(message)).unwrap();
+            return true;
+        }
+    };
+
+    let build = BuildResult {
+        success: false,
+        path: None,
+        log,
+    };
+    gui_sender.send(GuiMessage::PushBuild(build)).unwrap();
+    false
+}
+
 /// Opens a window to configure the users settings
 pub fn user_config(
     ctx: &Context,
     user_config: &mut UserConfig,
     block_cache: &mut BlockCache,
-    endpoint: &str,
-    api_key: &str,
-    stream_sender: &Sender<StreamMessages>,
+    api_key: &mut String,
+    api_key_saved: &mut bool,
+    channels: Channels,
+    map_modules: &[(String, String)],
 ) {
+    let endpoint = user_config.endpoint().url.clone();
+    // The key being typed, which only replaces the current key once it's saved
+    let draft_id = egui::Id::new("api_key_draft");
+
     Window::new("User Config").min_width(250.0).show(ctx, |ui| {
+        match crate::validate_api_key(api_key) {
+            Ok(()) => ui.colored_label(Color32::GREEN, "✔ API key set"),
+            Err(err) => ui
+                .colored_label(Color32::RED, "⚠ API key missing")
+                .on_hover_text(err),
+        };
+
+        let mut draft = ui
+            .data(|data| data.get_temp::<String>(draft_id))
+            .unwrap_or_else(|| api_key.clone());
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut draft)
+                    .password(true)
+                    .hint_text("API key"),
+            );
+            if ui
+                .add_enabled(draft != *api_key, egui::Button::new("Save"))
+                .on_hover_text(
+                    "Use this key from now on, instead of the API_KEY environment variable",
+                )
+                .clicked()
+            {
+                *api_key = draft.trim().to_string();
+                *api_key_saved = true;
+                draft = api_key.clone();
+            }
+        });
+        ui.data_mut(|data| data.insert_temp(draft_id, draft));
+
         ui.collapsing("Substream Config", |ui| {
-            ui.add(user_config);
+            ui.add(&mut *user_config);
         });
 
         ui.separator();
 
         ui.collapsing("Block Config", |ui| {
-            block_cache.show(ui, api_key, endpoint, stream_sender)
+            block_cache.show(
+                ui,
+                api_key,
+                &endpoint,
+                &user_config.endpoint_list,
+                channels,
+                map_modules,
+            )
         });
     });
 }
 
+/// Opens a window to write the generated source to a file
+pub fn save_source(
+    ctx: &Context,
+    open: &mut bool,
+    source_path: &mut String,
+    source_file: &str,
+    source_ok: bool,
+    module_name: &str,
+    gui_sender: &Sender<GuiMessage>,
+) {
+    if source_path.trim().is_empty() {
+        *source_path = format!("{}.rhai", module_name);
+    }
+
+    // Set when saving would replace an existing file, until the user confirms or changes the path
+    let confirm_id = egui::Id::new("save_source_confirm_overwrite");
+    let mut confirm_overwrite = ctx
+        .data(|data| data.get_temp::<bool>(confirm_id))
+        .unwrap_or_default();
+    let mut saved = false;
+
+    Window::new("Save Source")
+        .open(open)
+        .min_width(250.0)
+        .show(ctx, |ui| {
+            ui.label("Path");
+            if ui.text_edit_singleline(source_path).changed() {
+                confirm_overwrite = false;
+            }
+
+            if !source_ok {
+                ui.colored_label(Color32::RED, "Fix the module errors before saving");
+                return;
+            }
+
+            let save = if confirm_overwrite {
+                ui.colored_label(Color32::YELLOW, format!("{} already exists", source_path));
+                ui.horizontal(|ui| {
+                    let overwrite = ui.button("Overwrite").clicked();
+                    if ui.button("Cancel").clicked() {
+                        confirm_overwrite = false;
+                    }
+                    overwrite
+                })
+                .inner
+            } else if ui.button("Save").clicked() {
+                confirm_overwrite = Path::new(source_path.as_str()).exists();
+                !confirm_overwrite
+            } else {
+                false
+            };
+
+            if save {
+                confirm_overwrite = false;
+                match fs::write(&*source_path, source_file) {
+                    Ok(()) => {
+                        let message = format!("Saved source to {}", source_path);
+                        gui_sender.send(GuiMessage::PushMessage(message)).unwrap();
+                        saved = true;
+                    }
+                    Err(err) => {
+                        let message = format!("Failed to save source to {}: {}", source_path, err);
+                        gui_sender.send(GuiMessage::PushError(message)).unwrap();
+                    }
+                }
+            }
+        });
+
+    if saved {
+        *open = false;
+    }
+    ctx.data_mut(|data| data.insert_temp(confirm_id, confirm_overwrite));
+}
+
+/// Opens a window to export the project to a file or import one, returning what was clicked
+pub fn project(ctx: &Context, open: &mut bool, project_path: &mut String) -> Option<ProjectAction> {
+    if project_path.trim().is_empty() {
+        *project_path = "project.json".to_string();
+    }
+
+    let mut action = None;
+    Window::new("Project")
+        .open(open)
+        .min_width(250.0)
+        .show(ctx, |ui| {
+            ui.label("Path");
+            ui.text_edit_singleline(project_path);
+
+            ui.horizontal(|ui| {
+                if ui.button("Export").clicked() {
+                    action = Some(ProjectAction::Export);
+                }
+                if ui
+                    .button("Import")
+                    .on_hover_text("Replaces the current modules, ABIs and config")
+                    .clicked()
+                {
+                    action = Some(ProjectAction::Import);
+                }
+            });
+        });
+
+    if action.is_some() {
+        *open = false;
+    }
+    action
+}
+
+/// Asks whether to discard unsaved changes, giving `Some(true)` to go ahead and `Some(false)` to cancel
+///
+/// The rest of the app is dimmed and blocked while it's open, so it acts as a modal.
+pub fn confirm_discard(ctx: &Context) -> Option<bool> {
+    let screen = ctx.screen_rect();
+    egui::Area::new(egui::Id::new("confirm_discard_backdrop"))
+        .fixed_pos(screen.min)
+        .order(egui::Order::Foreground)
+        .show(ctx, |ui| {
+            ui.allocate_response(screen.size(), egui::Sense::click());
+            ui.painter()
+                .rect_filled(screen, 0.0, Color32::from_black_alpha(160));
+        });
+
+    // An area rather than a window, since windows can't be ordered above the backdrop
+    let mut choice = None;
+    egui::Area::new(egui::Id::new("confirm_discard"))
+        .order(egui::Order::Tooltip)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            egui::Frame::window(ui.style()).show(ui, |ui| {
+                ui.heading("Unsaved Changes");
+                ui.label("The project has changes that haven't been exported. Discard them?");
+                ui.horizontal(|ui| {
+                    if ui.button("Discard").clicked() {
+                        choice = Some(true);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        choice = Some(false);
+                    }
+                });
+            });
+        });
+    choice
+}
+
+/// Opens a window to see the loaded ABIs and load more from files, returning the name and path to load
+pub fn abi_manager(
+    ctx: &Context,
+    open: &mut bool,
+    abis: &HashMap<String, String>,
+) -> Option<(String, PathBuf)> {
+    let inputs_id = egui::Id::new("abi_manager_inputs");
+    let (mut name, mut path) = ctx
+        .data(|data| data.get_temp::<(String, String)>(inputs_id))
+        .unwrap_or_default();
+
+    let mut load = None;
+    Window::new("ABIs")
+        .open(open)
+        .min_width(250.0)
+        .show(ctx, |ui| {
+            let mut names = abis.keys().collect::<Vec<_>>();
+            names.sort();
+            for abi_name in names {
+                ui.label(abi_name);
+            }
+            ui.weak("Handlers can read these from the `ABIS` map");
+            ui.separator();
+
+            ui.label("Name");
+            ui.text_edit_singleline(&mut name);
+            ui.label("ABI Path");
+            ui.add(egui::TextEdit::singleline(&mut path).hint_text("abis/my_contract.json"));
+
+            if ui.button("Load").clicked() {
+                load = Some((name.clone(), PathBuf::from(path.trim())));
+            }
+        });
+
+    ctx.data_mut(|data| data.insert_temp(inputs_id, (name, path)));
+    load
+}
+
+/// Opens a window to scaffold modules from a subgraph graphql schema
+pub fn schema_import(
+    ctx: &Context,
+    open: &mut bool,
+    schema_path: &mut String,
+    modules: &mut HashMap<i64, Module>,
+    gui_sender: &Sender<GuiMessage>,
+) {
+    Window::new("Import GraphQL Schema")
+        .open(open)
+        .min_width(250.0)
+        .show(ctx, |ui| {
+            ui.label("Schema Path");
+            ui.text_edit_singleline(schema_path);
+
+            if ui.button("Import").clicked() {
+                let message = match fs::read_to_string(&*schema_path) {
+                    Ok(schema) => {
+                        let entities = schema_import::parse_entities(&schema);
+                        let mut imported = 0;
+                        for module in schema_import::scaffold_modules(&entities) {
+                            // Don't clobber modules the user already has
+                            if modules.values().any(|m| m.name() == module.name()) {
+                                continue;
+                            }
+                            modules.insert(random(), module);
+                            imported += 1;
+                        }
+
+                        if entities.is_empty() {
+                            format!("No @entity types found in {}", schema_path)
+                        } else {
+                            format!(
+                                "Imported {} modules for {} entities from {}",
+                                imported,
+                                entities.len(),
+                                schema_path
+                            )
+                        }
+                    }
+                    Err(err) => {
+                        let message = format!("Failed to read {}: {}", schema_path, err);
+                        gui_sender.send(GuiMessage::PushError(message)).unwrap();
+                        return;
+                    }
+                };
+                gui_sender.send(GuiMessage::PushMessage(message)).unwrap();
+            }
+        });
+}
+
 /// Opens a window to view the source code of the compiled rhai scripts
 pub fn rust_view_ui(ctx: &Context, code: &str) {
     let language = "rs";
@@ -42,69 +376,374 @@ pub fn rust_view_ui(ctx: &Context, code: &str) {
     });
 }
 
+/// Writes the messages to the file as newline delimited json, oldest first
+fn export_log(path: &Path, messages: &VecDeque<Message>) -> Result<(), String> {
+    if path.as_os_str().is_empty() {
+        return Err("Failed to export the log: no path was given".to_string());
+    }
+
+    let mut log = String::new();
+    for message in messages {
+        log.push_str(&message.log_entry().to_string());
+        log.push('\n');
+    }
+    fs::write(path, log)
+        .map_err(|err| format!("Failed to export the log to {}: {}", path.display(), err))
+}
+
+/// How many blocks either side of a message's block "Focus here" streams
+const FOCUS_WINDOW: u64 = 5;
+
+/// The messages and the panel's own settings
+pub struct MessageContext<'a> {
+    pub messages: &'a VecDeque<Message>,
+    /// How many older messages were dropped to stay under the cap
+    pub dropped_messages: usize,
+    pub stream_progress: &'a BTreeMap<u64, StreamProgress>,
+    pub message_search: &'a mut String,
+    pub redaction: &'a mut Redaction,
+    pub log_path: &'a mut String,
+}
+
 /// Shows the panel for the messages to the system
 pub fn message_panel(
     ui: &mut Ui,
-    messages: &Vec<MessageKind>,
-    message_search: &mut String,
-    gui_sender: &Sender<GuiMessage>,
-    worker_sender: &Sender<WorkerMessage>,
+    context: MessageContext,
+    view_config: &EditorViews,
+    stored_outputs: &mut BTreeMap<String, Value>,
+    editor_config: &mut EditorConfig,
+    channels: Channels,
 ) {
+    let MessageContext {
+        messages,
+        dropped_messages,
+        stream_progress,
+        message_search,
+        redaction,
+        log_path,
+    } = context;
+    let only_errors = view_config.show_only_errors;
+    let show_null_json = view_config.show_null_json;
+    let Channels {
+        gui: gui_sender,
+        worker: worker_sender,
+        ..
+    } = channels;
+
+    let output_name_id = egui::Id::new("stored_output_name");
+    let mut output_name = ui
+        .data(|data| data.get_temp::<String>(output_name_id))
+        .unwrap_or_else(|| "output".to_string());
+
     ScrollArea::vertical().show(ui, |ui| {
         ui.heading("Messages");
-        ui.text_edit_singleline(message_search);
+        for (id, progress) in stream_progress {
+            let text = format!("stream {}: {}", id, progress);
+            match progress.fraction() {
+                Some(fraction) => {
+                    ui.add(egui::ProgressBar::new(fraction).text(text));
+                }
+                None => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label(text);
+                    });
+                }
+            }
+        }
+        let term = message_search.trim().to_lowercase();
+        let shown = |message: &Message| {
+            let kind = &message.kind;
+            let empty = matches!(kind, MessageKind::JsonMessage(json) if is_empty_json(json));
+            (show_null_json || !empty)
+                && (!only_errors || kind.is_error())
+                && (term.is_empty() || kind.matches(&term))
+        };
+        ui.horizontal(|ui| {
+            let search = ui.text_edit_singleline(message_search);
+            focus::register(ui.ctx(), PanelFocus::Messages, search.id);
+            if !term.is_empty() {
+                let count = messages.iter().filter(|message| shown(message)).count();
+                ui.weak(format!("{} matches", count));
+            }
+        });
         ui.horizontal(|ui| {
             if ui.button("Clear Messages").clicked() {
                 gui_sender.send(GuiMessage::ClearMessages).unwrap();
             }
         });
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(log_path).hint_text("messages.ndjson"));
+            if ui
+                .add_enabled(!messages.is_empty(), egui::Button::new("Export Log"))
+                .on_hover_text("Write every message to the file as a line of json, with its time")
+                .clicked()
+            {
+                let message = match export_log(Path::new(log_path.trim()), messages) {
+                    Ok(()) => GuiMessage::PushMessage(format!(
+                        "Exported {} messages to {}",
+                        messages.len(),
+                        log_path.trim()
+                    )),
+                    Err(err) => GuiMessage::PushError(err),
+                };
+                gui_sender.send(message).unwrap();
+            }
+        });
+        ui.collapsing("Redaction", |ui| {
+            ui.checkbox(&mut redaction.enabled, "Redact JSON");
+            ui.add_enabled_ui(redaction.enabled, |ui| {
+                ui.checkbox(&mut redaction.mask_addresses, "Mask all addresses");
+                ui.checkbox(&mut redaction.mask_numbers, "Mask all numbers");
+                ui.label("Paths to mask (one per line, * matches any key)");
+                ui.text_edit_multiline(&mut redaction.paths);
+            });
+        });
+        ui.collapsing("Stored Outputs", |ui| {
+            ui.label("Name for the next stored output");
+            ui.text_edit_singleline(&mut output_name);
+            ui.separator();
+
+            if stored_outputs.is_empty() {
+                ui.weak("Use Store on a json message to keep it as a module input");
+            }
+            let mut removed = None;
+            for name in stored_outputs.keys() {
+                ui.horizontal(|ui| {
+                    ui.label(name);
+                    if ui.small_button("🗑").clicked() {
+                        removed = Some(name.clone());
+                    }
+                });
+            }
+            if let Some(name) = removed {
+                stored_outputs.remove(&name);
+            }
+        });
         ui.separator();
         ui.vertical(|ui| {
-            for (i, message) in messages.iter().enumerate() {
-                match message {
+            if dropped_messages > 0 {
+                ui.weak(format!("({} older messages hidden)", dropped_messages));
+            }
+
+            // Keeps the relative times ticking over while nothing else is happening
+            if !messages.is_empty() {
+                ui.ctx().request_repaint_after(Duration::from_secs(1));
+            }
+
+            // Offset by the dropped messages so a message keeps its id as older ones are dropped
+            for (i, message) in (dropped_messages..).zip(messages.iter()) {
+                if !shown(message) {
+                    continue;
+                }
+
+                match &message.kind {
                     MessageKind::JsonMessage(json) => {
-                        match &json {
-                            serde_json::Value::Null => continue,
-                            serde_json::Value::Array(arr) => {
-                                if arr.is_empty() {
-                                    continue;
-                                }
+                        let redacted;
+                        let shown_json = if redaction.enabled {
+                            redacted = redaction.apply(json);
+                            &redacted
+                        } else {
+                            json
+                        };
+
+                        ui.horizontal(|ui| {
+                            message_badges(ui, message);
+                            if ui.small_button("Copy").clicked() {
+                                // Copy what's displayed, so redacted values stay redacted
+                                let text = serde_json::to_string_pretty(shown_json)
+                                    .unwrap_or_else(|_| shown_json.to_string());
+                                ui.output_mut(|o| o.copied_text = text);
                             }
-                            serde_json::Value::Object(obj) => {
-                                if obj.is_empty() {
-                                    continue;
+
+                            if ui
+                                .small_button("Store")
+                                .on_hover_text("Keep this output to use as a module input")
+                                .clicked()
+                            {
+                                let name = unique_output_name(stored_outputs, &output_name);
+                                stored_outputs.insert(name.clone(), json.clone());
+                                worker_sender
+                                    .send(WorkerMessage::StoreOutput(name.clone(), json.clone()))
+                                    .unwrap();
+
+                                let message = format!("Stored output as `{}`", name);
+                                gui_sender.send(GuiMessage::PushMessage(message)).unwrap();
+                            }
+
+                            if let Some(number) = block_cache::block_number(json) {
+                                ui.weak(format!("Block {}", number));
+                                if ui.small_button("Focus here").clicked() {
+                                    let start = number.saturating_sub(FOCUS_WINDOW);
+                                    let stop = number + FOCUS_WINDOW;
+                                    editor_config.stream_start_block = start as i64;
+                                    editor_config.stream_stop_block = stop;
+
+                                    let message = format!(
+                                        "Stream config set to blocks {} to {}",
+                                        start, stop
+                                    );
+                                    gui_sender.send(GuiMessage::PushMessage(message)).unwrap();
                                 }
                             }
-                            _ => {}
-                        };
+                        });
 
                         let id = format!("json_message:{}", i);
-                        egui_json_tree::JsonTree::new(id, json)
+                        egui_json_tree::JsonTree::new(id, shown_json)
                             .default_expand(egui_json_tree::DefaultExpand::SearchResults(
                                 message_search,
                             ))
                             .show(ui);
                     }
                     MessageKind::TextMessage(msg) => {
-                        ui.label(msg);
+                        ui.horizontal_wrapped(|ui| {
+                            message_badges(ui, message);
+                            if ui.small_button("Copy").clicked() {
+                                ui.output_mut(|o| o.copied_text = msg.clone());
+                            }
+                            ui.label(msg);
+                        });
+                    }
+                    MessageKind::ErrorMessage(msg) => {
+                        ui.horizontal_wrapped(|ui| {
+                            message_badges(ui, message);
+                            if ui.small_button("Copy").clicked() {
+                                ui.output_mut(|o| o.copied_text = msg.clone());
+                            }
+                            ui.colored_label(Color32::RED, format!("⚠ {}", msg));
+                        });
+                    }
+                    MessageKind::BuildMessage(build) => {
+                        ui.horizontal_wrapped(|ui| {
+                            message_badges(ui, message);
+                            if ui.small_button("Copy").clicked() {
+                                ui.output_mut(|o| o.copied_text = build.log.clone());
+                            }
+                            if build.success {
+                                ui.colored_label(Color32::GREEN, "✔ Build succeeded");
+                            } else {
+                                ui.colored_label(Color32::RED, "⚠ Build failed");
+                            }
+                            if let Some(path) = &build.path {
+                                ui.label(path.display().to_string());
+                                if ui.small_button("Open build folder").clicked() {
+                                    if let Err(err) = open_folder(path) {
+                                        let message =
+                                            format!("Failed to open {}: {}", path.display(), err);
+                                        gui_sender.send(GuiMessage::PushError(message)).unwrap();
+                                    }
+                                }
+                            }
+                        });
+                        ui.label(&build.log);
                     }
                 }
             }
         });
     });
+
+    ui.data_mut(|data| data.insert_temp(output_name_id, output_name));
+}
+
+/// Opens the folder holding the path in the system file browser
+fn open_folder(path: &Path) -> io::Result<()> {
+    let folder = if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap_or(path)
+    };
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(target_os = "windows") {
+        "explorer"
+    } else {
+        "xdg-open"
+    };
+    Command::new(opener).arg(folder).spawn().map(|_| ())
+}
+
+/// Shows how long ago a message arrived, dimmed, and which stream it came from if it came from one
+fn message_badges(ui: &mut Ui, message: &Message) {
+    ui.label(egui::RichText::new(time_ago(message.time)).small().weak());
+    if let Some(source) = &message.source {
+        let badge = egui::RichText::new(source)
+            .small()
+            .background_color(ui.visuals().faint_bg_color);
+        ui.label(badge);
+    }
+}
+
+/// How long ago the time was in the largest whole unit, eg `2s ago`
+fn time_ago(time: SystemTime) -> String {
+    // A time in the future, from the clock being set back, counts as now
+    let secs = time.elapsed().map_or(0, |elapsed| elapsed.as_secs());
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// Null and empty outputs, which are hidden unless Show Null Json is on
+fn is_empty_json(json: &Value) -> bool {
+    match json {
+        Value::Null => true,
+        Value::Array(arr) => arr.is_empty(),
+        Value::Object(obj) => obj.is_empty(),
+        _ => false,
+    }
+}
+
+/// Returns `base`, or `base` with the first free numeric suffix if an output is already stored under it
+fn unique_output_name(stored_outputs: &BTreeMap<String, Value>, base: &str) -> String {
+    let base = if base.trim().is_empty() {
+        "output"
+    } else {
+        base.trim()
+    };
+    crate::modules::free_name(base, |name| stored_outputs.contains_key(name))
 }
 
-/// Shows the menu bar for the application
+/// The state of the project the menu bar shows and runs things with
+pub struct MenuContext<'a> {
+    pub api_key: &'a str,
+    pub source_file: &'a str,
+    pub source_map: &'a SourceMap,
+    /// Why the source couldn't be generated, when `source_file` is empty
+    pub source_errors: &'a [String],
+    pub modules: &'a HashMap<i64, Module>,
+    pub compile_status: &'a CompileStatus,
+    /// The label of each running stream, by task id
+    pub running_streams: &'a BTreeMap<u64, String>,
+}
+
+/// Shows the menu bar for the application, returning the project action picked from it
 pub fn menu_bar(
     ui: &mut Ui,
+    context: MenuContext,
     view_config: &mut EditorViews,
     editor_config: &mut EditorConfig,
-    template_repo_path: &mut String,
-    api_key: &str,
-    source_file: &str,
-    worker_sender: &Sender<WorkerMessage>,
-    stream_sender: &Sender<StreamMessages>,
-) {
+    display_welcome_message: &mut bool,
+    channels: Channels,
+    module_actions: &mut Vec<ModuleAction>,
+) -> Option<ProjectAction> {
+    let MenuContext {
+        api_key,
+        source_file,
+        source_map,
+        source_errors,
+        modules,
+        compile_status,
+        running_streams,
+    } = context;
+    let Channels {
+        gui: gui_sender,
+        worker: worker_sender,
+        stream: stream_sender,
+    } = channels;
+
+    let mut action = None;
     menu::bar(ui, |ui| {
         ui.menu_button("Panels", |ui| {
             ui.checkbox(&mut view_config.show_config, "Toggle Config Panel");
@@ -116,31 +755,206 @@ pub fn menu_bar(
                 "Toggle User Config Panel",
             );
             ui.checkbox(&mut view_config.show_null_json, "Show Null Json?");
+            ui.checkbox(&mut view_config.show_only_errors, "Show Only Errors");
             ui.checkbox(&mut view_config.show_full_source, "Show Full Source");
+            ui.checkbox(&mut view_config.show_schema_import, "Import GraphQL Schema");
+            ui.checkbox(&mut view_config.show_abis, "ABIs");
+            ui.menu_button("Theme", |ui| {
+                for theme in [Theme::Dark, Theme::Light] {
+                    ui.selectable_value(&mut view_config.theme, theme, theme.label());
+                }
+            });
+            ui.menu_button("Zoom", |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("-").clicked() {
+                        view_config.zoom -= 0.1;
+                    }
+                    if ui.button("Reset").clicked() {
+                        view_config.zoom = 1.0;
+                    }
+                    if ui.button("+").clicked() {
+                        view_config.zoom += 0.1;
+                    }
+                });
+                ui.add(
+                    egui::Slider::new(&mut view_config.zoom, MIN_ZOOM..=MAX_ZOOM).fixed_decimals(1),
+                );
+                view_config.zoom = view_config.zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+                if view_config.zoom != ui.ctx().zoom_factor() {
+                    ui.ctx().set_zoom_factor(view_config.zoom);
+                }
+            });
+            ui.separator();
+            ui.checkbox(
+                &mut view_config.show_welcome_on_startup,
+                "Show Welcome On Startup",
+            );
+            if ui.button("Show Welcome Message").clicked() {
+                *display_welcome_message = true;
+                ui.close_menu();
+            }
         });
 
         ui.menu_button("Run", |ui| {
-            if ui.button("Run in repl").clicked() {
-                let message = WorkerMessage::Eval(source_file.to_string());
+            let has_modules = !modules.is_empty();
+            // The source is empty while the modules have errors, so there'd be nothing to run
+            let runnable = has_modules && source_errors.is_empty();
+            let disabled_reason = if has_modules {
+                source_errors.join("\n")
+            } else {
+                NO_MODULES.to_string()
+            };
+            let run_in_repl = ui
+                .add_enabled(runnable, egui::Button::new("Run in repl"))
+                .on_hover_ui(|ui| {
+                    ui.label(format!(
+                        "Will register {} modules and run handlers:",
+                        modules.len()
+                    ));
+                    for module in modules.values() {
+                        ui.label(format!("• {}", module.name()));
+                    }
+                })
+                .on_disabled_hover_text(disabled_reason);
+            if run_in_repl.clicked() {
+                let message = WorkerMessage::EvalFresh(source_file.to_string(), source_map.clone());
                 worker_sender.send(message).unwrap();
             }
 
+            if ui
+                .button("Reset REPL")
+                .on_hover_text("Clear the repl's variables, functions and messages")
+                .clicked()
+            {
+                worker_sender.send(WorkerMessage::Reset).unwrap();
+                ui.close_menu();
+            }
+
+            ui.collapsing("Preview repl source", |ui| {
+                ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                    let theme = egui_extras::syntax_highlighting::CodeTheme::from_memory(ui.ctx());
+                    egui_extras::syntax_highlighting::code_view_ui(ui, &theme, source_file, "rs");
+                });
+            });
+
             if ui.button("Run a stream").clicked() {
-                let message = StreamMessages::Run {
-                    start: editor_config.stream_start_block,
-                    stop: editor_config.stream_stop_block,
-                    api_key: api_key.to_string(),
-                    package_file: editor_config.substream_package.clone(),
-                    endpoint: editor_config.substream_endpoint.clone(),
-                    module_name: editor_config.module_name.clone(),
-                };
-                stream_sender.send(message).unwrap()
+                if let Err(err) = editor_config.validate() {
+                    gui_sender.send(GuiMessage::PushError(err)).unwrap();
+                } else {
+                    let message = StreamMessages::Run {
+                        start: editor_config.stream_start_block,
+                        stop: editor_config.stream_stop_block,
+                        api_key: api_key.to_string(),
+                        package_file: editor_config.substream_package.clone(),
+                        endpoint: editor_config.substream_endpoint.clone(),
+                        module_names: std::iter::once(&editor_config.module_name)
+                            .chain(&editor_config.extra_output_modules)
+                            .filter(|name| !name.is_empty())
+                            .cloned()
+                            .collect(),
+                        webhook: Some(editor_config.webhook.clone())
+                            .filter(|webhook| webhook.is_enabled()),
+                    };
+                    stream_sender.send(message).unwrap()
+                }
             }
 
-            if ui.button("Build").clicked() {
-                let message = WorkerMessage::Build;
-                worker_sender.send(message).unwrap();
+            ui.menu_button("Stop", |ui| {
+                if running_streams.is_empty() {
+                    ui.weak("Nothing is running");
+                }
+                for (id, label) in running_streams {
+                    if ui.button(format!("stream {}: {}", id, label)).clicked() {
+                        stream_sender
+                            .send(StreamMessages::Cancel(Some(*id)))
+                            .unwrap();
+                        ui.close_menu();
+                    }
+                }
+                if running_streams.len() > 1 && ui.button("Stop All").clicked() {
+                    stream_sender.send(StreamMessages::Cancel(None)).unwrap();
+                    ui.close_menu();
+                }
+            });
+
+            if ui.button("Save Source…").clicked() {
+                view_config.show_save_source = true;
+                ui.close_menu();
+            }
+
+            if ui
+                .button("New Project")
+                .on_hover_text("Start over with the default modules and config")
+                .clicked()
+            {
+                action = Some(ProjectAction::New);
+                ui.close_menu();
+            }
+
+            // Both open the project window, which has the path and both actions
+            let export = ui.button("Export Project…").clicked();
+            let import = ui.button("Import Project…").clicked();
+            if export || import {
+                view_config.show_project = true;
+                ui.close_menu();
+            }
+
+            if ui
+                .add_enabled(has_modules, egui::Button::new("Build"))
+                .on_hover_text("Ctrl+B in a module")
+                .on_disabled_hover_text(NO_MODULES)
+                .clicked()
+            {
+                module_actions.push(ModuleAction::Build);
+            }
+
+            let generate = ui
+                .add_enabled(has_modules, egui::Button::new("Generate Only"))
+                .on_hover_text("Generate the source without building or streaming")
+                .on_disabled_hover_text(NO_MODULES)
+                .clicked();
+            let generate_and_save = ui
+                .add_enabled(has_modules, egui::Button::new("Generate and Save…"))
+                .on_disabled_hover_text(NO_MODULES)
+                .clicked();
+            if generate || generate_and_save {
+                let generated = generate_only(modules, source_file, source_errors, gui_sender);
+                view_config.show_full_source |= generated;
+                view_config.show_save_source |= generated && generate_and_save;
+                ui.close_menu();
             }
         });
+
+        ui.with_layout(Layout::right_to_left(Align::Center), |ui| {
+            compile_status_indicator(ui, compile_status, view_config);
+        });
     });
+    action
+}
+
+/// Shows whether the generated source compiles, clicking it toggles the error details
+fn compile_status_indicator(
+    ui: &mut Ui,
+    compile_status: &CompileStatus,
+    view_config: &mut EditorViews,
+) {
+    match compile_status {
+        CompileStatus::Unknown => {
+            ui.weak("Checking...");
+        }
+        CompileStatus::Ok => {
+            ui.colored_label(Color32::GREEN, "● Compiles");
+        }
+        CompileStatus::Error(err) => {
+            let response = ui
+                .add(
+                    egui::Label::new(egui::RichText::new("● Errors").color(Color32::RED))
+                        .sense(egui::Sense::click()),
+                )
+                .on_hover_text(err);
+            if response.clicked() {
+                view_config.show_compile_errors = !view_config.show_compile_errors;
+            }
+        }
+    }
 }